@@ -9,6 +9,7 @@ fn purely_latin_benchmark(c: &mut Criterion) {
 
     let alphabets_scheme=config.generate_alphabets_scheme();
     let punctuation_characters=&config.punctuation_characters;
+    let chunking_options=config.generate_chunking_options();
 
     //Prepare a text consisting purely of latin characters.
 
@@ -17,7 +18,7 @@ fn purely_latin_benchmark(c: &mut Criterion) {
         text+="aaaaaaaaaa";
         }
 
-    c.bench_function("pure latin", |b| b.iter(|| parse_text(black_box(&text), black_box(&alphabets_scheme), black_box(punctuation_characters), black_box(true))));
+    c.bench_function("pure latin", |b| b.iter(|| parse_text(black_box(&text), black_box(&alphabets_scheme), black_box(punctuation_characters), black_box(&chunking_options), black_box(true))));
 
     }
 fn purely_chinese_benchmark(c: &mut Criterion) {
@@ -27,6 +28,7 @@ fn purely_chinese_benchmark(c: &mut Criterion) {
 
     let alphabets_scheme=config.generate_alphabets_scheme();
     let punctuation_characters=&config.punctuation_characters;
+    let chunking_options=config.generate_chunking_options();
 
     //Prepare a text consisting purely of latin characters.
 
@@ -35,7 +37,7 @@ fn purely_chinese_benchmark(c: &mut Criterion) {
         text+="你好我是人嘛你又差嘛";
         }
 
-    c.bench_function("pure chinese", |b| b.iter(|| parse_text(black_box(&text), black_box(&alphabets_scheme), black_box(punctuation_characters), black_box(true))));
+    c.bench_function("pure chinese", |b| b.iter(|| parse_text(black_box(&text), black_box(&alphabets_scheme), black_box(punctuation_characters), black_box(&chunking_options), black_box(true))));
 
     }
 fn mixed_latin_and_chinese_benchmark(c: &mut Criterion) {
@@ -45,6 +47,7 @@ fn mixed_latin_and_chinese_benchmark(c: &mut Criterion) {
 
     let alphabets_scheme=config.generate_alphabets_scheme();
     let punctuation_characters=&config.punctuation_characters;
+    let chunking_options=config.generate_chunking_options();
 
     //Prepare a text consisting purely of latin characters.
 
@@ -53,7 +56,7 @@ fn mixed_latin_and_chinese_benchmark(c: &mut Criterion) {
         text+="hellothere你好我是人嘛你又差嘛";
         }
 
-    c.bench_function("mixed latin chinese", |b| b.iter(|| parse_text(black_box(&text), black_box(&alphabets_scheme), black_box(punctuation_characters), black_box(true))));
+    c.bench_function("mixed latin chinese", |b| b.iter(|| parse_text(black_box(&text), black_box(&alphabets_scheme), black_box(punctuation_characters), black_box(&chunking_options), black_box(true))));
 
     }
 
@@ -1,19 +1,48 @@
+pub mod protocol;
 pub mod text_processor;
+pub mod unicode_data;
 
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::fs;
+use std::fmt;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate lazy_static;
 use notify::Config as NotifyConfig;
 use notify::{event::EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use regex::Regex;
-use subprocess::{Exec, Popen, Redirection};
-use text_processor::LanguageChunk;
+use subprocess::{Exec, NullFile, Popen, Redirection};
+use protocol::{ParsedRequest, Request, RequestParser, Response, ResponseParser};
+use text_processor::{ChunkMerging, ChunkingOptions, LanguageChunk, LanguageDetection, NeutralCharacterPolicy};
+
+static NEXT_PROCESS_INSTANCE: AtomicU64=AtomicU64::new(0);
+//Editors often save a file in more steps, so the configuration is reloaded only after changes stop coming for a while.
+const CONFIGURATION_CHANGE_DEBOUNCE: Duration=Duration::from_millis(300);
+//Speech-dispatcher's requests wait while new modules are initialized during a reload, so their initialization is limited to a short time, even if their responses are not limited or are given more time.
+const INITIALIZATION_TIMEOUT: Duration=Duration::from_secs(5);
+//If a module can't be started again, another attempt is made only after this time, so a missing module doesn't keep Chinfusor busy.
+const RESTART_BACKOFF: Duration=Duration::from_secs(5);
+
+pub const ALPHABETS_FILE_NAME: &str="alphabets_settings.csv";
+pub const SETTINGS_FILE_NAME: &str="settings.conf";
+pub const CONFIGURATION_REPORT_FILE_NAME: &str="configuration_errors.log";
+pub const TOML_FILE_NAME: &str="chinfusor.toml";
+pub const SYSTEM_CONFIGURATION_DIRECTORY: &str="/etc/chinfusor";
+
+//Names of columns of alphabets_settings.csv, used in error reports.
+const ALPHABET_FIELD_NAMES: [&str; 13]=["alphabet", "unicode ranges", "module", "arg", "language", "voice", "punctuation mode", "pitch", "capitals pitch", "rate", "volume", "firejail", "response timeout"];
+//Keys of alphabet tables in chinfusor.toml, in the order of columns of alphabets_settings.csv.
+const TOML_ALPHABET_KEYS: [&str; 13]=["name", "unicode_ranges", "module", "arg", "language", "voice", "punctuation_mode", "pitch", "capitals_pitch", "rate", "volume", "firejail", "response_timeout"];
+const SETTING_NAMES: [&str; 11]=["punctuation_characters", "sound_icon_engine", "sound_icons_directory", "sound_icon_player", "overlapping_ranges", "neutral_characters", "minimum_chunk_length", "prefer_neighbours", "alphabet_groups", "language_detection_threshold", "speak_configuration_errors"];
 
 lazy_static! {
     static ref MINI_THREAD_POOL: MiniThreadPool=MiniThreadPool::new();
@@ -23,17 +52,15 @@ lazy_static! {
     static ref COLON_SEARCHING_REGEX: Regex=Regex::new(": ?").unwrap();
     }
 
-pub enum SdInputCommand {
-    Init,
-    Audio(AudioSettings),
-    LogLevel(LogLevelSettings),
-    Set(SpeechSettings),
-    Speak(String),
-    Char(char),
-    Key(String),
-    Pause,
-    Stop,
-    Quit,
+//Everything the main loop of Chinfusor reacts to comes through a single channel in form of this enum, so the loop can simply block until something happens.
+pub enum ChinfusorEvent {
+    SdInput(Request),
+    EngineReply(u64, Response),
+    EngineStdoutClosed(u64),
+    SoundIconPlayed(u32),
+    ConfigurationChange(notify::Result<notify::Event>),
+    //Returned by next_event, once no configuration change came for a while, so the configuration can be reloaded.
+    ConfigurationSettled,
     }
 pub struct AudioSettings {
     lines: Vec<String>,
@@ -44,8 +71,8 @@ impl AudioSettings {
         AudioSettings {lines}
         }
 
-    pub fn generate_sd_command(&self) -> String {
-        format!("AUDIO\n{}\n.\n", self.lines.join("\n"))
+    pub fn generate_sd_command(&self) -> Request {
+        Request::Audio(self.lines.clone())
         }
     }
 pub struct SpeechSettings {
@@ -57,17 +84,127 @@ impl SpeechSettings {
         SpeechSettings {lines}
         }
 
-    pub fn generate_sd_command(&self) -> String {
-        format!("SET\n{}\n.\n", self.lines.join("\n"))
+    pub fn generate_sd_command(&self) -> Request {
+        Request::Set(self.lines.clone())
         }
-    pub fn generate_sd_command_parametrized(language: &str) -> String {
-        format!("SET\nvolume=100\npitch=7\nvoice=male1\nlanguage={}\n.\n", language)
+    pub fn generate_sd_command_parametrized(language: &str) -> Request {
+        Request::Set(vec!["volume=100".to_string(), "pitch=7".to_string(), "voice=male1".to_string(), format!("language={}", language)])
         }
-    pub fn generate_sd_command_from_engine_configuration(config: &SpeechEngineConfiguration) -> String {
-        format!("SET\nlanguage={}\nvoice={}\npunctuation_mode={}\npitch={}\nrate={}\nvolume={}\n.\n", config.language, config.voice, config.punctuation_mode, config.pitch, config.rate, config.volume)
+    pub fn generate_sd_command_from_engine_configuration(config: &SpeechEngineConfiguration, parameters: &SdSpeechParameters) -> Request {
+        let punctuation_mode=parameters.get_forwarded_setting("punctuation_mode").unwrap_or(&config.punctuation_mode);
+
+        let mut lines=vec![format!("language={}", config.language), format!("voice={}", config.voice), format!("punctuation_mode={}", punctuation_mode), format!("pitch={}", parameters.pitch_for(config)), format!("rate={}", parameters.rate_for(config)), format!("volume={}", parameters.volume_for(config))];
+        for (key, value) in &parameters.forwarded_settings {
+            if key!="punctuation_mode" {
+                lines.push(format!("{}={}", key, value));
+                }
+            }
+        if let Some(synthesis_voice)=parameters.synthesis_voice_for(config) {
+            lines.push(format!("synthesis_voice={}", synthesis_voice));
+            }
+
+        Request::Set(lines)
+        }
+    pub fn generate_sd_command_from_pitch(pitch: i32) -> Request {
+        Request::Set(vec![format!("pitch={}", pitch)])
+        }
+
+    pub fn get_settings(&self) -> Vec<(String, String)> {
+        let mut result=Vec::new();
+        for line in &self.lines {
+            if let Some(position)=line.find('=') {
+                result.push((line[..position].trim().to_string(), line[position+1..].trim().to_string()));
+                }
+            }
+
+        result
+        }
+    }
+//Speech parameters requested by speech-dispatcher. Rate, pitch and volume are treated as offsets on top of values configured for each alphabet, so their mutual differences are preserved, while other settings are forwarded to the engines as they are.
+pub struct SdSpeechParameters {
+    pub rate: i32,
+    pub pitch: i32,
+    pub volume: i32,
+    pub forwarded_settings: Vec<(String, String)>,
+    //Voices selected from the list of all engines' voices, by names of alphabets they belong to.
+    pub synthesis_voices: Vec<(String, String)>,
+    }
+impl SdSpeechParameters {
+
+    pub fn new() -> SdSpeechParameters {
+        //Speech-dispatcher's default volume is 100, so this is the value, which leaves configured volumes untouched.
+        SdSpeechParameters {rate: 0, pitch: 0, volume: 100, forwarded_settings: Vec::new(), synthesis_voices: Vec::new()}
+        }
+
+    //Voices without a label of a known alphabet are assigned to the first, default one.
+    pub fn update(&mut self, settings: &SpeechSettings, config: &Config) {
+        for (key, value) in settings.get_settings() {
+            match &key[..] {
+                "rate" | "pitch" | "volume" => {
+                    if let Ok(n)=value.parse::<i32>() {
+                        let n=SdSpeechParameters::clamp(n);
+                        match &key[..] {
+                            "rate" => self.rate=n,
+                            "pitch" => self.pitch=n,
+                            _ => self.volume=n,
+                            };
+                        }
+                    },
+                //Language and voice are configured per alphabet, forwarding them would make all engines speak the same language.
+                "language" | "voice" => {},
+                "synthesis_voice" => {
+                    if value.len()==0 || value=="NULL" {
+                        continue;
+                        }
+
+                    let (alphabet, voice)=match split_voice_label(&value) {
+                        Some((voice, alphabet)) if config.engines.iter().any(|engine| engine.name==alphabet) => (alphabet.to_string(), voice.to_string()),
+                        _ => match config.engines.first() {
+                            Some(engine) => (engine.name.clone(), value.clone()),
+                            None => continue,
+                            },
+                        };
+
+                    if let Some(setting)=self.synthesis_voices.iter_mut().find(|(a, _)| *a==alphabet) {
+                        setting.1=voice;
+                        }
+                    else {
+                        self.synthesis_voices.push((alphabet, voice));
+                        }
+                    },
+                _ => {
+                    if let Some(setting)=self.forwarded_settings.iter_mut().find(|(k, _)| *k==key) {
+                        setting.1=value;
+                        }
+                    else {
+                        self.forwarded_settings.push((key, value));
+                        }
+                    },
+                };
+            }
+        }
+    pub fn get_forwarded_setting(&self, key: &str) -> Option<&String> {
+        self.forwarded_settings.iter().find(|(k, _)| k==key).map(|(_, v)| v)
         }
-    pub fn generate_sd_command_from_pitch(pitch: i32) -> String {
-        format!("SET\npitch={}\n.\n", pitch)
+    pub fn synthesis_voice_for(&self, config: &SpeechEngineConfiguration) -> Option<&String> {
+        self.synthesis_voices.iter().find(|(alphabet, _)| *alphabet==config.name).map(|(_, voice)| voice)
+        }
+
+    pub fn rate_for(&self, config: &SpeechEngineConfiguration) -> i32 {
+        SdSpeechParameters::clamp(config.rate+self.rate)
+        }
+    pub fn pitch_for(&self, config: &SpeechEngineConfiguration) -> i32 {
+        SdSpeechParameters::clamp(config.pitch+self.pitch)
+        }
+    pub fn capitals_pitch_for(&self, config: &SpeechEngineConfiguration) -> i32 {
+        SdSpeechParameters::clamp(config.capitals_pitch+self.pitch)
+        }
+    pub fn volume_for(&self, config: &SpeechEngineConfiguration) -> i32 {
+        SdSpeechParameters::clamp(config.volume+self.volume-100)
+        }
+
+    fn clamp(value: i32) -> i32 {
+        value.clamp(-100, 100)
         }
     }
 pub struct LogLevelSettings {
@@ -79,130 +216,642 @@ impl LogLevelSettings {
         LogLevelSettings {lines}
         }
 
-    pub fn generate_sd_command(&self) -> String {
-        format!("LOGLEVEL\n{}\n.\n", self.lines.join("\n"))
+    pub fn generate_sd_command(&self) -> Request {
+        Request::LogLevel(self.lines.clone())
         }
     }
+//Everything speech-dispatcher has set during the session, engines started later are brought to the same state.
+struct SessionSettings {
+    audio: Option<AudioSettings>,
+    log_level: Option<LogLevelSettings>,
+    speech_parameters: SdSpeechParameters,
+    }
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputKind {
+    Stdout,
+    Stderr,
+    }
 pub enum MiniThreadPoolRequest {
-    ReadUntilSdEndSignal(Arc<Mutex<File>>, mpsc::Sender<String>),
+    Watch(File, u64, OutputKind, mpsc::Sender<ChinfusorEvent>),
+    }
+//A player of a sound icon file, it's shared with the thread waiting for its end.
+struct SoundIconPlayer {
+    pid: u32,
+    process: Arc<Mutex<Popen>>,
+    }
+struct WatchedOutput {
+    file: File,
+    instance: u64,
+    kind: OutputKind,
+    events_transmitter: mpsc::Sender<ChinfusorEvent>,
+    line_buffer: Vec<u8>,
+    reply_parser: ResponseParser,
     }
+impl WatchedOutput {
+
+    //Returns false if nobody is interested in the output anymore.
+    fn process_input(&mut self, input: &[u8]) -> bool {
+        for byte in input {
+            if *byte!=b'\n' {
+                self.line_buffer.push(*byte);
+                continue;
+                }
+
+            let line=String::from_utf8_lossy(&self.line_buffer).to_string();
+            self.line_buffer.clear();
+
+            match self.kind {
+                OutputKind::Stdout => {
+                    if let Some(reply)=self.reply_parser.parse_line(&line) {
+                        if self.events_transmitter.send(ChinfusorEvent::EngineReply(self.instance, reply)).is_err() {
+                            return false;
+                            }
+                        }
+                    },
+                OutputKind::Stderr => log(&line),
+                };
+            }
+
+        true
+        }
+    fn close(self) {
+        if self.kind==OutputKind::Stdout {
+            self.events_transmitter.send(ChinfusorEvent::EngineStdoutClosed(self.instance)).unwrap_or(());
+            }
+        }
+    }
+//The pool reads output of all speech modules continuously using just one thread. It waits with poll for any of the watched pipes to become readable, new pipes are announced through a channel accompanied by a byte written to the wake up pipe.
 pub struct MiniThreadPool {
     requests_transmitter: Mutex<mpsc::Sender<MiniThreadPoolRequest>>,
+    wake_up_pipe: File,
     }
 impl MiniThreadPool {
 
     pub fn new() -> MiniThreadPool {
         let (tx, rx)=mpsc::channel::<MiniThreadPoolRequest>();
 
-        thread::spawn(move || MiniThreadPool::mini_thread_pool_loop(rx));
+        let mut fds: [libc::c_int; 2]=[0; 2];
+        //Modules must not inherit the pipe, so it's closed on exec.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }!=0 {
+            panic!("Unable to create the wake up pipe of the mini thread pool.");
+            }
+        let (wake_up_pipe_reader, wake_up_pipe)=unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        thread::spawn(move || MiniThreadPool::mini_thread_pool_loop(rx, wake_up_pipe_reader));
 
-        MiniThreadPool {requests_transmitter: Mutex::new(tx)}
+        MiniThreadPool {requests_transmitter: Mutex::new(tx), wake_up_pipe}
         }
 
-    pub fn get_requests_transmitter(&self) -> mpsc::Sender<MiniThreadPoolRequest> {
+    pub fn watch(&self, file: File, instance: u64, kind: OutputKind, events_transmitter: mpsc::Sender<ChinfusorEvent>) {
         let requests_transmitter=self.requests_transmitter.lock().unwrap();
-        requests_transmitter.clone()
+        requests_transmitter.send(MiniThreadPoolRequest::Watch(file, instance, kind, events_transmitter)).unwrap();
+        (&self.wake_up_pipe).write_all(&[0]).unwrap();
         }
 
-    fn mini_thread_pool_loop(requests_receiver: mpsc::Receiver<MiniThreadPoolRequest>) {
+    fn mini_thread_pool_loop(requests_receiver: mpsc::Receiver<MiniThreadPoolRequest>, mut wake_up_pipe: File) {
+        let mut watched_outputs: Vec<WatchedOutput>=Vec::new();
+        let mut reading_buffer: Vec<u8>=vec![0; 4096];
 
-        let mut stdout_reading_buffer: Vec<u8>=Vec::with_capacity(1000);
-        let mut small_stdout_reading_buffer: Vec<u8>=vec![0;1];
+        loop {
+            let mut poll_fds=vec![libc::pollfd {fd: wake_up_pipe.as_raw_fd(), events: libc::POLLIN, revents: 0}];
+            for output in &watched_outputs {
+                poll_fds.push(libc::pollfd {fd: output.file.as_raw_fd(), events: libc::POLLIN, revents: 0});
+                }
 
-        while let Ok(request)=requests_receiver.recv() {
-            match request {
-                MiniThreadPoolRequest::ReadUntilSdEndSignal(stdout, stdout_transmitter) => {
-                    let mut stdout=stdout.lock().unwrap();
-                    let mut end_signal_detected=false;
+            if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1) }<0 {
+                //Interrupted by a signal
+                continue;
+                }
 
-                    while !end_signal_detected {
-                        if let Ok(n)=stdout.read(&mut small_stdout_reading_buffer) {
-                            if n==0 {
-                                break;
-                                }
-                            }
-                        else {
-                            break;
-                            }
+            if poll_fds[0].revents!=0 {
+                //The written bytes carry no information, the pipe just needs to be emptied.
+                let _=wake_up_pipe.read(&mut reading_buffer);
 
-                        if small_stdout_reading_buffer[0]!='\n' as u8 {
-                            stdout_reading_buffer.push(small_stdout_reading_buffer[0]);
-                            }
-                        else {
-                            let line=String::from_utf8(stdout_reading_buffer.clone()).unwrap();
+                loop {
+                    match requests_receiver.try_recv() {
+                        Ok(MiniThreadPoolRequest::Watch(file, instance, kind, events_transmitter)) => {
+                            watched_outputs.push(WatchedOutput {file, instance, kind, events_transmitter, line_buffer: Vec::new(), reply_parser: ResponseParser::new()});
+                            },
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => return,
+                        };
+                    }
+                }
 
-                            end_signal_detected=line=="702 END" || line=="703 STOP" || line=="704 PAUSE";
+            //Outputs added just now are not in poll_fds yet, they'll be polled in the next round.
+            let mut closed_outputs=Vec::new();
+            for (i, output) in watched_outputs.iter_mut().enumerate() {
+                match poll_fds.get(i+1) {
+                    Some(poll_fd) if poll_fd.revents!=0 => {},
+                    _ => continue,
+                    };
 
-                            stdout_transmitter.send(line).unwrap();
+                let still_open=match output.file.read(&mut reading_buffer) {
+                    Ok(0) | Err(_) => false,
+                    Ok(n) => output.process_input(&reading_buffer[..n]),
+                    };
 
-                            stdout_reading_buffer.clear();
-                            }
-                        }
-                    },
-                };
+                if !still_open {
+                    closed_outputs.push(i);
+                    }
+                }
+
+            for i in closed_outputs.into_iter().rev() {
+                watched_outputs.remove(i).close();
+                }
             }
         }
     }
 pub struct Process {
+    file_path: String,
+    arg: String,
+    firejailed: bool,
+    response_timeout: Duration,
     process: Popen,
+    instance: u64,
     stdin: File,
-    stdout: Arc<Mutex<File>>,
-    stdout_transmitter: mpsc::Sender<String>,
-    stdout_receiver: mpsc::Receiver<String>,
-    mini_thread_pool_requests_transmitter: mpsc::Sender<MiniThreadPoolRequest>,
+    events_transmitter: mpsc::Sender<ChinfusorEvent>,
+    initialized: bool,
+    //Set while waiting for the reply to INIT sent by initialize method.
+    initializing: bool,
+    initialization_failed: bool,
+    audio_settings: Option<Request>,
+    log_level_settings: Option<Request>,
+    speech_settings: Vec<(String, String)>,
+    //Commands sent to the module, which are still waiting for their replies, along with the expected reply codes.
+    awaited_replies: VecDeque<(u64, &'static str, u32)>,
+    sent_commands: u64,
+    speaking: bool,
+    //Set once the module began speaking the current utterance. Long utterances may not produce any output for a long time, so the response timeout applies only until then.
+    speech_began: bool,
+    last_activity: Instant,
+    last_write_failed: bool,
+    failed: bool,
+    //Set when the module couldn't be started again, the process is dead until the next successful restart.
+    spawn_failed_at: Option<Instant>,
+    spawned_at: Instant,
+    //Set once the module was told to quit or killed, so its exit is expected.
+    quitting: bool,
+    voices: Option<Vec<String>>,
+    voices_requested: bool,
     }
 impl Process {
 
-    pub fn new(file_path: &str, arg: &str, firejailed: bool) -> Process {
-        let process=if !firejailed {
-            Exec::cmd(file_path).arg(arg).stdin(Redirection::Pipe).stdout(Redirection::Pipe).popen().expect(&format!("Unable to start {}.", file_path))
+    pub fn new(file_path: &str, arg: &str, firejailed: bool, response_timeout: Duration, events_transmitter: mpsc::Sender<ChinfusorEvent>) -> Process {
+        Process::try_new(file_path, arg, firejailed, response_timeout, events_transmitter).unwrap_or_else(|e| panic!("{}", e))
+        }
+    pub fn try_new(file_path: &str, arg: &str, firejailed: bool, response_timeout: Duration, events_transmitter: mpsc::Sender<ChinfusorEvent>) -> Result<Process, String> {
+        let (process, stdin, stdout, stderr)=Process::spawn(file_path, arg, firejailed)?;
+        let instance=NEXT_PROCESS_INSTANCE.fetch_add(1, Ordering::SeqCst);
+
+        Process::watch_output(instance, stdout, stderr, &events_transmitter);
+
+        Ok(Process {file_path: file_path.to_string(), arg: arg.to_string(), firejailed, response_timeout, process, instance, stdin, events_transmitter, initialized: false, initializing: false, initialization_failed: false, audio_settings: None, log_level_settings: None, speech_settings: Vec::new(), awaited_replies: VecDeque::new(), sent_commands: 0, speaking: false, speech_began: false, last_activity: Instant::now(), last_write_failed: false, failed: false, spawn_failed_at: None, spawned_at: Instant::now(), quitting: false, voices: None, voices_requested: false})
+        }
+
+    pub fn instance(&self) -> u64 {
+        self.instance
+        }
+    //Tells, whether the process runs the module of the given engine the way it's configured, so it doesn't need to be restarted when settings of the engine change.
+    pub fn runs(&self, engine: &SpeechEngineConfiguration) -> bool {
+        self.file_path==engine.module && self.arg==engine.arg && self.firejailed==engine.firejailed
+        }
+    pub fn set_response_timeout(&mut self, response_timeout: Duration) {
+        self.response_timeout=response_timeout;
+        }
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+        }
+    //Sends INIT and tracks its result, which is available through initialization_result method. Unlike other failures, failed initialization doesn't restart the module.
+    pub fn initialize(&mut self) {
+        self.remember_state(&Request::Init);
+        self.initializing=true;
+        self.initialization_failed=false;
+        self.last_activity=Instant::now();
+
+        if !self.send(&Request::Init) {
+            log(&format!("Unable to write to {}.", self.file_path));
+            self.initializing=false;
+            self.initialization_failed=true;
+            }
+        }
+    //Returns None while the module didn't answer INIT yet, otherwise whether it initialized successfully.
+    pub fn initialization_result(&self) -> Option<bool> {
+        if self.initializing {
+            None
+            }
+        else {
+            Some(!self.initialization_failed)
+            }
+        }
+    //Every reply of the module should pass through this method, so the process can check, whether its commands succeeded, and whether the module is still speaking.
+    pub fn accept_reply(&mut self, reply: &Response) {
+        self.last_activity=Instant::now();
+
+        match reply {
+            Response::End | Response::Stop | Response::Pause => self.speaking=false,
+            Response::Begin => self.speech_began=true,
+            Response::Ok(code, _, text) | Response::Error(code, text) => {
+                if let Some((command_id, command, expected_code))=self.awaited_replies.pop_front() {
+                    if command=="INIT" && self.initializing {
+                        self.initializing=false;
+                        self.initialization_failed=*code!=expected_code;
+                        }
+                    if command=="LIST VOICES" && self.voices_requested {
+                        self.voices_requested=false;
+                        self.voices=Some(match reply {
+                            Response::Ok(200, voices, _) => voices.clone(),
+                            _ => Vec::new(),
+                            });
+                        }
+
+                    if *code!=expected_code {
+                        log(&format!("{} answered {} {} to {}.", self.file_path, code, text, command));
+
+                        //The remaining replies of the failed command won't come.
+                        while self.awaited_replies.front().map(|(id, _, _)| *id)==Some(command_id) {
+                            self.awaited_replies.pop_front();
+                            }
+
+                        //A message, which was refused, will never end on its own.
+                        if self.speaking && Request::is_speech_command(command) {
+                            self.speaking=false;
+                            self.failed=true;
+                            self.events_transmitter.send(ChinfusorEvent::EngineReply(self.instance, Response::End)).unwrap();
+                            }
+                        }
+                    }
+                },
+            _ => {},
+            };
+        }
+    //If the module died in the middle of an utterance, the end signal will never come. Restarting the module reports the utterance as finished. A module, which died while idle, is restarted as well, so the next request doesn't get lost.
+    pub fn handle_stdout_closed(&mut self, instance: u64) {
+        if instance==self.instance && self.initializing {
+            log(&format!("{} exited during initialization.", self.file_path));
+            self.initializing=false;
+            self.initialization_failed=true;
+            return;
+            }
+
+        if instance!=self.instance || self.quitting {
+            return;
+            }
+
+        if self.speaking || self.voices_requested {
+            log(&format!("{} exited unexpectedly, restarting it.", self.file_path));
+            self.restart();
+            }
+        //A module, which dies right after its start, is left alone until it's needed, so it doesn't keep being restarted in vain.
+        else if self.spawned_at.elapsed()>=RESTART_BACKOFF {
+            log(&format!("{} exited, restarting it.", self.file_path));
+            self.restart();
+            }
+        }
+    //Returns the time remaining until the module is considered frozen, if it's currently expected to respond.
+    pub fn time_until_timeout(&self) -> Option<Duration> {
+        let response_timeout=if self.initializing && (self.response_timeout==Duration::from_secs(0) || self.response_timeout>INITIALIZATION_TIMEOUT) {
+            INITIALIZATION_TIMEOUT
             } else {
-            Exec::cmd("firejail").args(&vec![file_path, arg]).stdin(Redirection::Pipe).stdout(Redirection::Pipe).stderr(Redirection::Pipe).popen().expect(&format!("Unable to start {}.", file_path))
+            self.response_timeout
+            };
+
+        if !((self.speaking && !self.speech_began) || self.voices_requested || self.initializing) || response_timeout==Duration::from_secs(0) {
+            return None;
+            }
+
+        Some(response_timeout.checked_sub(self.last_activity.elapsed()).unwrap_or(Duration::from_secs(0)))
+        }
+    pub fn check_timeout(&mut self) {
+        if self.initializing && self.time_until_timeout()==Some(Duration::from_secs(0)) {
+            log(&format!("{} didn't answer INIT in time.", self.file_path));
+            self.initializing=false;
+            self.initialization_failed=true;
+            return;
+            }
+
+        if self.time_until_timeout()==Some(Duration::from_secs(0)) {
+            log(&format!("{} didn't respond for {} seconds, restarting it.", self.file_path, self.response_timeout.as_secs()));
+            self.restart();
+            }
+        }
+    //Returns true once after the end signal of an utterance was produced by Chinfusor because the module failed.
+    pub fn take_failure(&mut self) -> bool {
+        let failed=self.failed;
+        self.failed=false;
+        failed
+        }
+    pub fn write(&mut self, request: &Request) {
+        self.remember_state(request);
+
+        //A module asked to stop or pause must answer in time again, so a frozen one can't block the interruption.
+        if self.speaking && (*request==Request::Stop || *request==Request::Pause) {
+            self.speech_began=false;
+            self.last_activity=Instant::now();
+            }
+
+        if self.send(request) {
+            self.last_write_failed=false;
+            return;
+            }
+
+        log(&format!("Unable to write to {}, restarting it.", self.file_path));
+        self.restart();
+
+        //The new instance gets the request as well, unless it couldn't be started.
+        self.last_write_failed=self.spawn_failed_at.is_some() || !self.send(request);
+        }
+    //Sends a message to be spoken, the end of the utterance will be signalled by Response::End.
+    pub fn speak(&mut self, request: &Request) {
+        self.write(request);
+        self.failed=false;
+
+        //The message didn't reach the module, so there is nothing to wait for.
+        if self.last_write_failed {
+            self.failed=true;
+            self.events_transmitter.send(ChinfusorEvent::EngineReply(self.instance, Response::End)).unwrap();
+            return;
+            }
+
+        self.speaking=true;
+        self.speech_began=false;
+        self.last_activity=Instant::now();
+        }
+    //Asks the module for its voices, the list is available through voices method once the module answers.
+    pub fn request_voices(&mut self) {
+        self.voices=None;
+        self.voices_requested=true;
+        self.last_activity=Instant::now();
+
+        self.write(&Request::ListVoices);
+        }
+    pub fn voices(&self) -> Option<&Vec<String>> {
+        self.voices.as_ref()
+        }
+    pub fn quit(&mut self) {
+        self.quitting=true;
+
+        //A dead module doesn't need to be restarted just to be told to quit.
+        self.stdin.write_all(Request::Quit.encode().as_bytes()).unwrap_or(());
+        }
+    pub fn wait_for_exit(&mut self) {
+        self.process.wait().unwrap();
+        }
+    //Used for modules, which failed, so they might not be able to quit on their own.
+    pub fn kill(&mut self) {
+        self.quitting=true;
+        self.process.kill().unwrap_or(());
+        self.process.wait().ok();
+        }
+
+    //Starts the module again with the same parameters and brings it to the state, in which the previous instance was. If an utterance was in progress, it's reported as finished, even if the module can't be started.
+    fn restart(&mut self) {
+        //A module, which had to be restarted, has no voices to offer.
+        if self.voices_requested {
+            self.voices_requested=false;
+            self.voices=Some(Vec::new());
+            }
+
+        self.respawn();
+
+        if self.speaking {
+            self.speaking=false;
+            self.failed=true;
+            self.events_transmitter.send(ChinfusorEvent::EngineReply(self.instance, Response::End)).unwrap();
+            }
+        }
+    fn respawn(&mut self) {
+        if let Some(spawn_failed_at)=self.spawn_failed_at {
+            if spawn_failed_at.elapsed()<RESTART_BACKOFF {
+                return;
+                }
+            }
+
+        self.process.kill().unwrap_or(());
+        self.process.wait().ok();
+
+        let (process, stdin, stdout, stderr)=match Process::spawn(&self.file_path, &self.arg, self.firejailed) {
+            Ok(result) => result,
+            Err(e) => {
+                log(&format!("{} Trying again in {} seconds.", e, RESTART_BACKOFF.as_secs()));
+                self.spawn_failed_at=Some(Instant::now());
+                return;
+                },
             };
+        self.spawn_failed_at=None;
+        self.spawned_at=Instant::now();
+
+        //Replies of the dead instance may still be on their way, they must not be confused with output of the new one.
+        self.instance=NEXT_PROCESS_INSTANCE.fetch_add(1, Ordering::SeqCst);
+        Process::watch_output(self.instance, stdout, stderr, &self.events_transmitter);
+
+        self.process=process;
+        self.stdin=stdin;
+        self.awaited_replies.clear();
+
+        let mut state=Vec::new();
+        if self.initialized {
+            state.push(Request::Init);
+            }
+        if let Some(audio_settings)=&self.audio_settings {
+            state.push(audio_settings.clone());
+            }
+        if let Some(log_level_settings)=&self.log_level_settings {
+            state.push(log_level_settings.clone());
+            }
+        if self.speech_settings.len()>0 {
+            let lines: Vec<String>=self.speech_settings.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            state.push(Request::Set(lines));
+            }
+        for request in state {
+            self.send(&request);
+            }
+        }
+    //Writes the request to the module and registers replies, which it should produce. Returns false if the module can't be written to.
+    fn send(&mut self, request: &Request) -> bool {
+        self.sent_commands+=1;
+        for code in request.expected_reply_codes() {
+            self.awaited_replies.push_back((self.sent_commands, request.name(), *code));
+            }
+
+        self.stdin.write_all(request.encode().as_bytes()).is_ok()
+        }
+    fn remember_state(&mut self, request: &Request) {
+        match request {
+            Request::Init => self.initialized=true,
+            Request::Audio(_) => self.audio_settings=Some(request.clone()),
+            Request::LogLevel(_) => self.log_level_settings=Some(request.clone()),
+            Request::Set(lines) => {
+                for line in lines {
+                    if let Some(position)=line.find('=') {
+                        let (key, value)=(&line[..position], &line[position+1..]);
+
+                        if let Some(setting)=self.speech_settings.iter_mut().find(|(k, _)| k==key) {
+                            setting.1=value.to_string();
+                            }
+                        else {
+                            self.speech_settings.push((key.to_string(), value.to_string()));
+                            }
+                        }
+                    }
+                },
+            _ => {},
+            };
+        }
+    fn watch_output(instance: u64, stdout: File, stderr: Option<File>, events_transmitter: &mpsc::Sender<ChinfusorEvent>) {
+        MINI_THREAD_POOL.watch(stdout, instance, OutputKind::Stdout, events_transmitter.clone());
+
+        if let Some(stderr)=stderr {
+            MINI_THREAD_POOL.watch(stderr, instance, OutputKind::Stderr, events_transmitter.clone());
+            }
+        }
+    fn spawn(file_path: &str, arg: &str, firejailed: bool) -> Result<(Popen, File, File, Option<File>), String> {
+        let process=if !firejailed {
+            Exec::cmd(file_path).arg(arg).stdin(Redirection::Pipe).stdout(Redirection::Pipe).popen()
+            } else {
+            Exec::cmd("firejail").args(&vec![file_path, arg]).stdin(Redirection::Pipe).stdout(Redirection::Pipe).stderr(Redirection::Pipe).popen()
+            }.map_err(|_| format!("Unable to start {}.", file_path))?;
         let stdin=if let Some(f)=&process.stdin {
-            (*f).try_clone().unwrap()
+            (*f).try_clone().map_err(|_| format!("Unable to take stdin of {}.", file_path))?
             } else {
-            panic!("Unable to take stdin of {}.", file_path);
+            return Err(format!("Unable to take stdin of {}.", file_path));
             };
         let stdout=if let Some(f)=&process.stdout {
-            Arc::new(Mutex::new((*f).try_clone().unwrap()))
+            (*f).try_clone().map_err(|_| format!("Unable to take stdout of {}.", file_path))?
             } else {
-            panic!("Unable to take stdout of {}.", file_path);
+            return Err(format!("Unable to take stdout of {}.", file_path));
+            };
+        let stderr=match &process.stderr {
+            Some(f) => Some((*f).try_clone().map_err(|_| format!("Unable to take stderr of {}.", file_path))?),
+            None => None,
             };
 
-        let (stdout_transmitter, stdout_receiver)=mpsc::channel::<String>();
+        Ok((process, stdin, stdout, stderr))
+        }
+    }
+//Decides, which alphabet gets characters, which fall into ranges of more alphabets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverlapPolicy {
+    FirstDefined,
+    MoreSpecific,
+    }
+//Describes an interval of characters claimed by two alphabets at once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeOverlap {
+    pub first_alphabet: String,
+    pub second_alphabet: String,
+    pub start: u32,
+    pub end: u32,
+    }
+impl fmt::Display for RangeOverlap {
 
-        let mini_thread_pool_requests_transmitter=MINI_THREAD_POOL.get_requests_transmitter();
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unicode ranges of alphabets {} and {} overlap in interval u0x{:X}-u0x{:X}.", self.first_alphabet, self.second_alphabet, self.start, self.end)
+        }
+    }
+//A problem found in a configuration file. Lines and columns are numbered from 1, line 0 denotes the file as a whole.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub field: String,
+    pub message: String,
+    }
+impl ConfigError {
 
-        Process {process, stdin, stdout, stdout_transmitter, stdout_receiver, mini_thread_pool_requests_transmitter}
+    pub fn new(file: &str, line: usize, column: usize, field: &str, message: &str) -> ConfigError {
+        ConfigError {file: file.to_string(), line, column, field: field.to_string(), message: message.to_string()}
         }
+    }
+impl fmt::Display for ConfigError {
 
-    pub fn read_line(&mut self) -> Option<String> {
-        if let Ok(line)=self.stdout_receiver.try_recv() {
-            return Some(line);
-            }
-        else {
-            return None;
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line==0 {
+            return write!(f, "{}: {}.", self.file, self.message);
             }
+
+        write!(f, "{}, line {}, column {}, {}: {}.", self.file, self.line, self.column, self.field, self.message)
         }
-    pub fn write(&mut self, input: &str) {
-        self.stdin.write(input.as_bytes()).unwrap();
+    }
+//Everything found wrong while loading the configuration.
+#[derive(Clone, Debug)]
+pub struct ConfigReport {
+    pub errors: Vec<ConfigError>,
+    pub overlaps: Vec<RangeOverlap>,
+    //Set, when an alphabet or a whole file couldn't be loaded at all, so the configuration lacks something, what the user asked for.
+    pub alphabets_dropped: bool,
+    }
+impl ConfigReport {
+
+    pub fn new() -> ConfigReport {
+        ConfigReport {errors: Vec::new(), overlaps: Vec::new(), alphabets_dropped: false}
         }
-    pub fn write_line(&mut self, input: &str) {
-        self.stdin.write(input.as_bytes()).unwrap();
-        self.stdin.write(&vec! ['\n' as u8]).unwrap();
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.len()==0 && self.overlaps.len()==0
         }
-    pub fn wait_for_exit(&mut self) {
-        self.process.wait().unwrap();
+    pub fn extend(&mut self, report: ConfigReport) {
+        self.errors.extend(report.errors);
+        self.overlaps.extend(report.overlaps);
+        self.alphabets_dropped|=report.alphabets_dropped;
+        }
+    pub fn log(&self) {
+        for line in self.to_string().lines() {
+            log(line);
+            }
+        }
+    //The report is written only if there's something to report, otherwise an outdated report is removed, so its presence alone tells, that something is wrong.
+    pub fn write_to_file(&self, file_path: &str) {
+        if self.is_empty() {
+            fs::remove_file(file_path).unwrap_or(());
+            return;
+            }
+
+        if fs::write(file_path, self.to_string()).is_err() {
+            log(&format!("Unable to write the configuration report to {}.", file_path));
+            }
         }
+    //A short version of the report, which can be spoken.
+    pub fn generate_summary(&self) -> String {
+        let count=self.errors.len()+self.overlaps.len();
+        let first=match self.errors.first() {
+            Some(error) => error.to_string(),
+            None => self.overlaps.first().map(|overlap| overlap.to_string()).unwrap_or(String::new()),
+            };
+
+        format!("Chinfusor found {} {} in its configuration, see {}. {}", count, if count==1 { "problem" } else { "problems" }, CONFIGURATION_REPORT_FILE_NAME, first)
+        }
+    }
+impl fmt::Display for ConfigReport {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "{}", error)?;
+            }
+        for overlap in &self.overlaps {
+            writeln!(f, "{}", overlap)?;
+            }
 
-    pub fn activate_asynchronous_reading_until_sd_end_signal(&self) {
-        self.mini_thread_pool_requests_transmitter.send(MiniThreadPoolRequest::ReadUntilSdEndSignal(self.stdout.clone(), self.stdout_transmitter.clone())).unwrap();
+        Ok(())
         }
     }
 pub struct Config {
     pub engines: Vec<SpeechEngineConfiguration>,
     pub punctuation_characters: HashSet<char>,
+    pub overlap_policy: OverlapPolicy,
+    pub neutral_character_policy: NeutralCharacterPolicy,
+    //The default minimum length of chunks and minimum lengths of particular alphabets, by their names
+    pub minimum_chunk_length: usize,
+    pub alphabet_minimum_chunk_lengths: Vec<(String, usize)>,
+    pub neighbour_preferring_alphabets: Vec<String>,
+    //Names of alphabets in groups, the first one of each group takes over chunks of the others next to it.
+    pub alphabet_groups: Vec<Vec<String>>,
+    pub language_detection_threshold: f64,
+    pub sound_icon_engine: Option<String>,
+    pub sound_icons_directory: Option<String>,
+    pub sound_icon_player: String,
+    pub speak_configuration_errors: bool,
     }
 impl Config {
 
@@ -210,110 +859,549 @@ impl Config {
         let engines=vec![SpeechEngineConfiguration::new("latin")];
         let punctuation_characters: HashSet<char>=[',', '.', '?', '，', '。', '？', '-', ' ', ':', '\r', '\n'].iter().cloned().collect();
 
-        Config { engines, punctuation_characters }
+        Config { engines, punctuation_characters, sound_icon_engine: None, sound_icons_directory: None, sound_icon_player: "paplay".to_string(), overlap_policy: OverlapPolicy::FirstDefined, neutral_character_policy: NeutralCharacterPolicy::Previous, minimum_chunk_length: 0, alphabet_minimum_chunk_lengths: Vec::new(), neighbour_preferring_alphabets: Vec::new(), alphabet_groups: Vec::new(), language_detection_threshold: 0.3, speak_configuration_errors: false }
         }
 
-    pub fn load_alphabets_from_file(&mut self, file_path: &str) {
-        if let Ok(s)=fs::read_to_string(file_path) {
-            self.load_alphabets_from_string(&s);
+    //Unlike loading from a string, loading from a file checks also, whether the speech modules and their arguments exist. If the file doesn't exist, defaults are kept.
+    pub fn load_alphabets_from_file(&mut self, file_path: &str) -> ConfigReport {
+        match fs::read_to_string(file_path) {
+            Ok(s) => self.load_alphabets(&s, true),
+            Err(e) if e.kind()==std::io::ErrorKind::NotFound => ConfigReport::new(),
+            Err(e) => {
+                let mut report=ConfigReport::new();
+                report.errors.push(ConfigError::new(ALPHABETS_FILE_NAME, 0, 0, "", &format!("unable to read the file, {}", e)));
+                report.alphabets_dropped=true;
+                report
+                },
             }
         }
-    pub fn load_alphabets_from_string(&mut self, s: &str) {
-        let mut engines=Vec::new();
-        for line in s.lines() {
-            if line.starts_with("#") {
+    //Overlaps of ranges of the loaded alphabets are included in the report. They're resolved according to the overlap policy when generating the alphabets scheme.
+    pub fn load_alphabets_from_string(&mut self, s: &str) -> ConfigReport {
+        self.load_alphabets(s, false)
+        }
+    pub fn load_configuration_from_file(&mut self, file_path: &str) -> ConfigReport {
+        match fs::read_to_string(file_path) {
+            Ok(s) => self.load_configuration_from_string(&s),
+            Err(e) if e.kind()==std::io::ErrorKind::NotFound => ConfigReport::new(),
+            Err(e) => {
+                let mut report=ConfigReport::new();
+                report.errors.push(ConfigError::new(SETTINGS_FILE_NAME, 0, 0, "", &format!("unable to read the file, {}", e)));
+                report
+                },
+            }
+        }
+    pub fn load_configuration_from_string(&mut self, s: &str) -> ConfigReport {
+        let mut report=ConfigReport::new();
+
+        for (i, line) in s.lines().enumerate() {
+            if line.starts_with("#") || line.trim().len()==0 {
                 continue;
                 }
 
-            if let Ok(engine)=SpeechEngineConfiguration::load_from_string(line) {
-                engines.push(engine);
+            let setting=match Config::split_by_colon(line) {
+                Ok(setting) => setting,
+                Err(_) => {
+                    report.errors.push(ConfigError::new(SETTINGS_FILE_NAME, i+1, 1, "", "expected a setting in form key: value"));
+                    continue;
+                    },
+                };
+            let (key, value)=(&setting[0], &setting[1]);
+
+            if !SETTING_NAMES.contains(&&key[..]) {
+                report.errors.push(ConfigError::new(SETTINGS_FILE_NAME, i+1, 1, key, "unknown setting"));
+                continue;
+                }
+            if let Err(message)=self.apply_setting(key, value) {
+                let value_column=line.chars().count()-value.chars().count()+1;
+                report.errors.push(ConfigError::new(SETTINGS_FILE_NAME, i+1, value_column, key, &message));
                 }
             }
 
-        //Latin engine is necessary and has to be specified exactly once, so we need to remove duplicates and check, whether it's there at all. We also need to make sure, that it's first in the list.
+        report
+        }
+    //Loads the configuration from the given directory. chinfusor.toml takes precedence over the older configuration files.
+    pub fn load_from_directory(directory: &Path) -> (Config, ConfigReport) {
+        let toml_file_path=directory.join(TOML_FILE_NAME);
+        if toml_file_path.exists() {
+            return Config::load_toml_from_file(&toml_file_path.to_string_lossy());
+            }
 
-        let mut latin_position=None;
-        for (i, engine) in engines.iter().enumerate() {
-            if engine.unicode_ranges.len()==0 {
-                latin_position=Some(i);
+        let mut config=Config::new();
+        let mut report=ConfigReport::new();
+        report.extend(config.load_alphabets_from_file(&directory.join(ALPHABETS_FILE_NAME).to_string_lossy()));
+        report.extend(config.load_configuration_from_file(&directory.join(SETTINGS_FILE_NAME).to_string_lossy()));
+
+        (config, report)
+        }
+    //Unlike alphabets_settings.csv and settings.conf, chinfusor.toml contains the whole configuration, so it's loaded into a fresh configuration. Missing settings and fields keep their defaults.
+    pub fn load_toml_from_file(file_path: &str) -> (Config, ConfigReport) {
+        let mut config=Config::new();
+
+        let report=match fs::read_to_string(file_path) {
+            Ok(s) => config.load_toml(&s, true),
+            Err(e) => {
+                let mut report=ConfigReport::new();
+                report.errors.push(ConfigError::new(TOML_FILE_NAME, 0, 0, "", &format!("unable to read the file, {}", e)));
+                report.alphabets_dropped=true;
+                report
+                },
+            };
+
+        (config, report)
+        }
+    pub fn load_toml_from_string(s: &str) -> (Config, ConfigReport) {
+        let mut config=Config::new();
+        let report=config.load_toml(s, false);
+
+        (config, report)
+        }
+    //Converts alphabets_settings.csv and settings.conf into the content of chinfusor.toml. Values are taken as they were written, so they're checked once the result is loaded. Comments are kept, lines, which can't be converted, are commented out.
+    pub fn convert_to_toml(alphabets: &str, settings: &str) -> String {
+        let mut result=String::new();
+
+        for line in settings.lines() {
+            if line.starts_with("#") || line.trim().len()==0 {
+                result.push_str(&format!("{}\n", line));
+                continue;
                 }
+
+            match Config::split_by_colon(line) {
+                Ok(setting) if setting[0]=="speak_configuration_errors" && Config::parse_boolean(&setting[1]).is_some() => result.push_str(&format!("{} = {}\n", setting[0], Config::parse_boolean(&setting[1]).unwrap())),
+                //Punctuation characters are taken literally, including spaces.
+                Ok(setting) if setting[0]=="punctuation_characters" => result.push_str(&format!("{} = {}\n", setting[0], toml::Value::String(setting[1].clone()))),
+                Ok(setting) => result.push_str(&format!("{} = {}\n", toml_key(&setting[0]), toml::Value::String(setting[1].trim().to_string()))),
+                Err(_) => result.push_str(&format!("#{}\n", line)),
+                };
             }
 
-        if let Some(position)=latin_position {
+        let mut converted_alphabets: Vec<String>=Vec::new();
+        for line in alphabets.lines() {
+            if line.starts_with("#") || line.trim().len()==0 {
+                result.push_str(&format!("{}\n", line));
+                continue;
+                }
 
-            let mut engines_to_remove=Vec::new();
-            for (i, engine) in engines.iter().enumerate().rev() {
-                if engine.unicode_ranges.len()==0 {
-                    if i>position {
-                        engines_to_remove.push(position);
-                        }
-                    else {
-                        break;
-                        }
-                    }
+            let fields: Vec<&str>=line.split(',').collect();
+            if fields.len()!=12 && fields.len()!=13 {
+                result.push_str(&format!("#{}\n", line));
+                continue;
                 }
-            for i in engines_to_remove {
-                engines.remove(i);
+
+            //TOML doesn't allow to define a table twice, so later alphabets with an already converted name are commented out like other lines, which can't be converted.
+            let key=toml_key(fields[0]);
+            if converted_alphabets.contains(&key) {
+                result.push_str(&format!("#{}\n", line));
+                continue;
                 }
 
-            if position>0 {
-                let latin_engine=engines.remove(position);
-                engines.insert(0, latin_engine);
+            result.push_str(&format!("\n[alphabets.{}]\n", key));
+            converted_alphabets.push(key);
+            for (i, field) in fields.iter().enumerate().skip(1) {
+                let value=match i {
+                    7..=10 | 12 => match field.parse::<i64>() {
+                        Ok(n) => toml::Value::Integer(n),
+                        Err(_) => toml::Value::String(field.to_string()),
+                        },
+                    11 => match Config::parse_boolean(field) {
+                        Some(value) => toml::Value::Boolean(value),
+                        None => toml::Value::String(field.to_string()),
+                        },
+                    _ => toml::Value::String(field.to_string()),
+                    };
+
+                result.push_str(&format!("{} = {}\n", TOML_ALPHABET_KEYS[i], value));
                 }
             }
-        else {
-            engines.insert(0, SpeechEngineConfiguration::new("latin"));
+
+        result
+        }
+    //Sound icons are played by the configured engine, if there's one with the given name, otherwise by the Latin one.
+    pub fn get_sound_icon_engine_id(&self) -> usize {
+        match &self.sound_icon_engine {
+            Some(name) => self.engines.iter().position(|engine| engine.name==*name).unwrap_or(0),
+            None => 0,
             }
+        }
+    //Returns path to the file of the sound icon, if it can be played without the help of a speech engine.
+    pub fn get_sound_icon_file(&self, icon: &str) -> Option<String> {
+        let directory=self.sound_icons_directory.as_ref()?;
+        let path=std::path::Path::new(directory).join(icon);
 
-        self.engines=engines;
+        if icon.contains('/') || !path.is_file() {
+            return None;
+            }
+
+        path.to_str().map(|path| path.to_string())
         }
-    pub fn load_configuration_from_file(&mut self, file_path: &str) {
-        if let Ok(s)=fs::read_to_string(file_path) {
-            self.load_configuration_from_string(&s);
+    //Describes the loaded configuration in a human readable form, with ranges and settings resolved as Chinfusor uses them.
+    pub fn generate_description(&self) -> String {
+        let mut result=String::new();
+        let yes_no=|value: bool| if value { "yes" } else { "no" };
+
+        result.push_str("Alphabets:\n");
+        for engine in &self.engines {
+            let ranges: Vec<String>=engine.unicode_ranges.chunks(2).map(|range| format!("u0x{:X}-u0x{:X}", range[0], range[1])).collect();
+
+            result.push_str(&format!("{}\n", engine.name));
+            result.push_str(&format!("    unicode ranges: {}\n", if engine.language_detected { "detected (latin text in its language)".to_string() } else if ranges.len()>0 { ranges.join(", ") } else { "* (everything not claimed by other alphabets)".to_string() }));
+            result.push_str(&format!("    module: {}\n    arg: {}\n", engine.module, engine.arg));
+            result.push_str(&format!("    language: {}, voice: {}, punctuation mode: {}\n", engine.language, engine.voice, engine.punctuation_mode));
+            result.push_str(&format!("    pitch: {}, capitals pitch: {}, rate: {}, volume: {}\n", engine.pitch, engine.capitals_pitch, engine.rate, engine.volume));
+            result.push_str(&format!("    firejail: {}, response timeout: {} s\n", yes_no(engine.firejailed), engine.response_timeout));
+            }
+
+        result.push_str("Resolved ranges:\n");
+        for triplet in self.generate_alphabets_scheme().chunks(3) {
+            result.push_str(&format!("    u0x{:X}-u0x{:X}: {}\n", triplet[0], triplet[1], self.engines[triplet[2] as usize].name));
             }
+
+        let mut punctuation_characters: Vec<char>=self.punctuation_characters.iter().cloned().collect();
+        punctuation_characters.sort();
+        let punctuation_characters: String=punctuation_characters.iter().map(|ch| ch.escape_debug().to_string()).collect();
+        result.push_str(&format!("Punctuation characters: {}\n", punctuation_characters));
+
+        result.push_str(&format!("Overlapping ranges: {}\n", match self.overlap_policy {
+            OverlapPolicy::FirstDefined => "first_defined",
+            OverlapPolicy::MoreSpecific => "more_specific",
+            }));
+        result.push_str(&format!("Neutral characters: {}\n", match self.neutral_character_policy {
+            NeutralCharacterPolicy::Previous => "previous",
+            NeutralCharacterPolicy::Next => "next",
+            NeutralCharacterPolicy::Disabled => "none",
+            }));
+        let alphabet_minimum_chunk_lengths: String=self.alphabet_minimum_chunk_lengths.iter().map(|(name, length)| format!(", {} {}", name, length)).collect();
+        result.push_str(&format!("Minimum chunk length: {}{}\n", self.minimum_chunk_length, alphabet_minimum_chunk_lengths));
+        result.push_str(&format!("Language detection threshold: {}\n", self.language_detection_threshold));
+        result.push_str(&format!("Prefer neighbours: {}\n", if self.neighbour_preferring_alphabets.len()>0 { self.neighbour_preferring_alphabets.join(", ") } else { "none".to_string() }));
+        let alphabet_groups: Vec<String>=self.alphabet_groups.iter().map(|group| group.join("+")).collect();
+        result.push_str(&format!("Alphabet groups: {}\n", if alphabet_groups.len()>0 { alphabet_groups.join(", ") } else { "none".to_string() }));
+        result.push_str(&format!("Sound icon engine: {}\n", self.engines[self.get_sound_icon_engine_id()].name));
+        result.push_str(&format!("Sound icons directory: {}\n", self.sound_icons_directory.as_ref().map(|directory| &directory[..]).unwrap_or("none")));
+        result.push_str(&format!("Sound icon player: {}\n", self.sound_icon_player));
+        result.push_str(&format!("Speak configuration errors: {}\n", yes_no(self.speak_configuration_errors)));
+
+        result
         }
-    pub fn load_configuration_from_string(&mut self, s: &str) {
-        for line in s.lines() {
-            if line.starts_with("#") || line=="\n" {
-                continue;
-                }
+    pub fn find_range_overlaps(&self) -> Vec<RangeOverlap> {
+        let mut result: Vec<RangeOverlap>=Vec::new();
+
+        for (first_id, first) in self.engines.iter().enumerate() {
+            for second in self.engines.iter().skip(first_id+1) {
+                let mut overlaps: Vec<(u32, u32)>=Vec::new();
+
+                for i in (0..first.unicode_ranges.len()).step_by(2) {
+                    for j in (0..second.unicode_ranges.len()).step_by(2) {
+                        let start=first.unicode_ranges[i].max(second.unicode_ranges[j]);
+                        let end=first.unicode_ranges[i+1].min(second.unicode_ranges[j+1]);
 
-            if let Ok(setting)=Config::split_by_colon(line) {
-                let (key, value)=(&setting[0], &setting[1]);
-                match &key[..] {
-                    "punctuation_characters" => {
-                        let mut punctuation_characters: HashSet<char>=['\n', '\r'].iter().cloned().collect();
-                        for ch in value.chars() {
-                            punctuation_characters.insert(ch);
+                        if start<=end {
+                            overlaps.push((start, end));
                             }
-                        self.punctuation_characters=punctuation_characters;
-                        },
-                    _ => {},
-                    };
+                        }
+                    }
+
+                //Names of scripts and blocks may produce many adjacent ranges, the overlaps are reported merged.
+                overlaps.sort();
+                let mut merged_overlaps: Vec<(u32, u32)>=Vec::new();
+                for (start, end) in overlaps {
+                    if let Some(last)=merged_overlaps.last_mut() {
+                        if start<=last.1.saturating_add(1) {
+                            last.1=last.1.max(end);
+                            continue;
+                            }
+                        }
+
+                    merged_overlaps.push((start, end));
+                    }
+
+                for (start, end) in merged_overlaps {
+                    result.push(RangeOverlap {first_alphabet: first.name.clone(), second_alphabet: second.name.clone(), start, end});
+                    }
                 }
             }
+
+        result
+        }
+    //Bundles everything parse_text needs to know about the configuration beside the alphabets scheme and punctuation.
+    pub fn generate_chunking_options(&self) -> ChunkingOptions {
+        ChunkingOptions {neutral_character_policy: self.neutral_character_policy, chunk_merging: self.generate_chunk_merging(), language_detection: self.generate_language_detection()}
+        }
+    //Collects alphabets, whose text is selected by detection of its language. The latin alphabet comes first, as it reads everything, what isn't detected reliably.
+    pub fn generate_language_detection(&self) -> LanguageDetection {
+        let mut alphabets: Vec<(usize, String)>=vec![(0, self.engines[0].language.clone())];
+        for (id, engine) in self.engines.iter().enumerate().filter(|(_, engine)| engine.language_detected) {
+            alphabets.push((id, engine.language.clone()));
+            }
+
+        LanguageDetection {alphabets, threshold: self.language_detection_threshold, ..LanguageDetection::default()}
+        }
+    //Generates rules for merging short chunks, indexed by ids of alphabets like the alphabets scheme.
+    pub fn generate_chunk_merging(&self) -> ChunkMerging {
+        let minimum_lengths=self.engines.iter()
+        .map(|engine| self.alphabet_minimum_chunk_lengths.iter().rev().find(|(name, _)| *name==engine.name).map(|(_, length)| *length).unwrap_or(self.minimum_chunk_length))
+        .collect();
+        let preferring_neighbours=self.engines.iter()
+        .map(|engine| self.neighbour_preferring_alphabets.contains(&engine.name))
+        .collect();
+
+        //Groups refer to alphabets by ids, unknown names are left out.
+        let find_id=|name: &String| self.engines.iter().position(|engine| engine.name==*name);
+        let alphabet_groups=self.alphabet_groups.iter()
+        .filter_map(|group| {
+            let leading_alphabet_id=find_id(&group[0])?;
+            let member_ids: Vec<usize>=group[1..].iter().filter_map(find_id).filter(|id| *id!=leading_alphabet_id).collect();
+
+            if member_ids.len()>0 { Some((leading_alphabet_id, member_ids)) } else { None }
+            })
+        .collect();
+
+        ChunkMerging {minimum_lengths, preferring_neighbours, alphabet_groups}
         }
+    //The scheme consists of triplets lower boundary, upper boundary and id of the alphabet, sorted by boundaries. Where ranges overlap, the alphabet selected by the overlap policy is used, so the triplets never overlap.
     pub fn generate_alphabets_scheme(&self) -> Vec<u32> {
-        let mut preresult: Vec<(u32, u32, usize)>=Vec::new();
+        let mut ranges: Vec<(u32, u32, usize)>=Vec::new();
         for (id, engine) in self.engines.iter().enumerate() {
-            if engine.unicode_ranges.len()!=0 {
-                for i in (0..engine.unicode_ranges.len()).step_by(2) {
-                    preresult.push((engine.unicode_ranges[i], engine.unicode_ranges[i+1], id));
-                    }
+            for i in (0..engine.unicode_ranges.len()).step_by(2) {
+                ranges.push((engine.unicode_ranges[i], engine.unicode_ranges[i+1], id));
                 }
             }
-        preresult.sort_by_key(|i| i.0);
+
+        //Split the characters into segments, in which the same set of ranges applies.
+        let mut boundaries: Vec<u32>=Vec::new();
+        for (start, end, _) in &ranges {
+            boundaries.push(*start);
+            boundaries.push(end.saturating_add(1));
+            }
+        boundaries.sort();
+        boundaries.dedup();
 
         let mut result: Vec<u32>=Vec::new();
-        for p in preresult {
-            result.push(p.0);
-            result.push(p.1);
-            result.push(p.2 as u32);
+        for segment in boundaries.windows(2) {
+            let (start, end)=(segment[0], segment[1]-1);
+
+            let candidates=ranges.iter().filter(|(range_start, range_end, _)| *range_start<=start && end<=*range_end);
+            let winner=match self.overlap_policy {
+                OverlapPolicy::FirstDefined => candidates.min_by_key(|(_, _, id)| *id),
+                OverlapPolicy::MoreSpecific => candidates.min_by_key(|(range_start, range_end, id)| (range_end-range_start, *id)),
+                };
+
+            let id=match winner {
+                Some((_, _, id)) => *id as u32,
+                None => continue,
+                };
+
+            //Merge with the previous segment, if it continues it.
+            let length=result.len();
+            if length>=3 && result[length-1]==id && result[length-2]+1==start {
+                result[length-2]=end;
+                continue;
+                }
+
+            result.push(start);
+            result.push(end);
+            result.push(id);
             }
 
         return result;
         }
 
+    fn load_alphabets(&mut self, s: &str, check_files: bool) -> ConfigReport {
+        let mut report=ConfigReport::new();
+
+        let mut engines: Vec<SpeechEngineConfiguration>=Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            if line.starts_with("#") || line.trim().len()==0 {
+                continue;
+                }
+
+            match SpeechEngineConfiguration::load_from_string(line, i+1, check_files, &mut report.errors) {
+                Some(engine) => engines.push(engine),
+                None => report.alphabets_dropped=true,
+                };
+            }
+
+        self.set_engines(engines, ALPHABETS_FILE_NAME, &mut report);
+        report
+        }
+    //Takes loaded engines as the new list of alphabets. Problems are reported as problems of the given file.
+    fn set_engines(&mut self, mut engines: Vec<SpeechEngineConfiguration>, file: &str, report: &mut ConfigReport) {
+        //Latin engine is necessary and has to be specified exactly once, so we need to remove duplicates and check, whether it's there at all. We also need to make sure, that it's first in the list.
+
+        let mut latin_line=None;
+        let mut i=0;
+        while i<engines.len() {
+            if engines[i].unicode_ranges.len()==0 && !engines[i].language_detected {
+                match latin_line {
+                    Some(line) => {
+                        report.errors.push(ConfigError::new(file, engines[i].line, 1, "alphabet", &format!("the latin alphabet is already defined on line {}, this definition is ignored", line)));
+                        engines.remove(i);
+                        continue;
+                        },
+                    None => {
+                        latin_line=Some(engines[i].line);
+                        let latin_engine=engines.remove(i);
+                        engines.insert(0, latin_engine);
+                        },
+                    };
+                }
+
+            i+=1;
+            }
+
+        if latin_line.is_none() {
+            engines.insert(0, SpeechEngineConfiguration::new("latin"));
+            }
+
+        self.engines=engines;
+
+        report.overlaps=self.find_range_overlaps();
+        }
+    //Applies a setting of settings.conf or chinfusor.toml. The key must be one of SETTING_NAMES, an invalid value is described by the returned error.
+    fn apply_setting(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "punctuation_characters" => {
+                let mut punctuation_characters: HashSet<char>=['\n', '\r'].iter().cloned().collect();
+                for ch in value.chars() {
+                    punctuation_characters.insert(ch);
+                    }
+                self.punctuation_characters=punctuation_characters;
+                },
+            "sound_icon_engine" => self.sound_icon_engine=Some(value.trim().to_string()),
+            "sound_icons_directory" => {
+                self.sound_icons_directory=Some(value.trim().to_string());
+
+                if !Path::new(value.trim()).is_dir() {
+                    return Err(format!("directory {} doesn't exist", value.trim()));
+                    }
+                },
+            "sound_icon_player" => self.sound_icon_player=value.trim().to_string(),
+            "overlapping_ranges" => {
+                match value.trim() {
+                    "first_defined" => self.overlap_policy=OverlapPolicy::FirstDefined,
+                    "more_specific" => self.overlap_policy=OverlapPolicy::MoreSpecific,
+                    _ => return Err(format!("expected first_defined or more_specific, found \"{}\"", value.trim())),
+                    };
+                },
+            "neutral_characters" => {
+                match value.trim() {
+                    "previous" => self.neutral_character_policy=NeutralCharacterPolicy::Previous,
+                    "next" => self.neutral_character_policy=NeutralCharacterPolicy::Next,
+                    "none" => self.neutral_character_policy=NeutralCharacterPolicy::Disabled,
+                    _ => return Err(format!("expected previous, next or none, found \"{}\"", value.trim())),
+                    };
+                },
+            //The value is the default length optionally followed by lengths of particular alphabets, like 2, chinese 1.
+            "minimum_chunk_length" => {
+                let mut minimum_chunk_length=0;
+                let mut alphabet_minimum_chunk_lengths: Vec<(String, usize)>=Vec::new();
+
+                for item in value.split(',').map(|item| item.trim()).filter(|item| item.len()>0) {
+                    let parts: Vec<&str>=item.split_whitespace().collect();
+                    match (parts.len(), parts.last().unwrap().parse::<usize>()) {
+                        (1, Ok(length)) => minimum_chunk_length=length,
+                        (2, Ok(length)) => alphabet_minimum_chunk_lengths.push((parts[0].to_string(), length)),
+                        _ => return Err(format!("expected a number or an alphabet name followed by a number, found \"{}\"", item)),
+                        };
+                    }
+
+                self.minimum_chunk_length=minimum_chunk_length;
+                self.alphabet_minimum_chunk_lengths=alphabet_minimum_chunk_lengths;
+                },
+            "language_detection_threshold" => {
+                match value.trim().parse::<f64>() {
+                    Ok(threshold) if threshold>=0.0 && threshold<=1.0 => self.language_detection_threshold=threshold,
+                    _ => return Err(format!("expected a number from 0 to 1, found \"{}\"", value.trim())),
+                    };
+                },
+            //Groups are separated by commas, alphabets in a group by pluses, like japanese+chinese.
+            "alphabet_groups" => {
+                let mut alphabet_groups: Vec<Vec<String>>=Vec::new();
+
+                for group in value.split(',').map(|group| group.trim()).filter(|group| group.len()>0) {
+                    let names: Vec<String>=group.split('+').map(|name| name.trim().to_string()).collect();
+                    if names.len()<2 || names.iter().any(|name| name.len()==0) {
+                        return Err(format!("expected names of alphabets joined by +, found \"{}\"", group));
+                        }
+
+                    alphabet_groups.push(names);
+                    }
+
+                self.alphabet_groups=alphabet_groups;
+                },
+            "prefer_neighbours" => self.neighbour_preferring_alphabets=value.split(',').map(|name| name.trim().to_string()).filter(|name| name.len()>0).collect(),
+            "speak_configuration_errors" => {
+                match Config::parse_boolean(value) {
+                    Some(value) => self.speak_configuration_errors=value,
+                    None => return Err(format!("expected yes or no, found \"{}\"", value.trim())),
+                    };
+                },
+            _ => return Err("unknown setting".to_string()),
+            };
+
+        Ok(())
+        }
+    fn load_toml(&mut self, s: &str, check_files: bool) -> ConfigReport {
+        let mut report=ConfigReport::new();
+
+        let table=match s.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => return report,
+            Err(e) => {
+                let (line, column)=e.line_col().map(|(line, column)| (line+1, column+1)).unwrap_or((0, 0));
+                let message=e.to_string();
+                let message=match message.find(" at line ") {
+                    Some(position) => &message[..position],
+                    None => &message[..],
+                    };
+
+                report.errors.push(ConfigError::new(TOML_FILE_NAME, line, column, "", message));
+                report.alphabets_dropped=true;
+                return report;
+                },
+            };
+
+        let mut engines: Vec<SpeechEngineConfiguration>=Vec::new();
+        for (key, value) in &table {
+            let (line, column)=find_toml_position(s, None, key);
+
+            if key=="alphabets" {
+                let alphabets=match value.as_table() {
+                    Some(alphabets) => alphabets,
+                    None => {
+                        report.errors.push(ConfigError::new(TOML_FILE_NAME, line, column, key, "expected tables of alphabets"));
+                        report.alphabets_dropped=true;
+                        continue;
+                        },
+                    };
+
+                for (name, alphabet) in alphabets {
+                    match load_toml_alphabet(s, name, alphabet, check_files, &mut report.errors) {
+                        Some(engine) => engines.push(engine),
+                        None => report.alphabets_dropped=true,
+                        };
+                    }
+                continue;
+                }
+
+            if !SETTING_NAMES.contains(&&key[..]) {
+                report.errors.push(ConfigError::new(TOML_FILE_NAME, line, 1, key, "unknown setting"));
+                continue;
+                }
+            if let Err(message)=toml_value_to_string(value, toml_list_separators(key)).and_then(|value| self.apply_setting(key, &value)) {
+                report.errors.push(ConfigError::new(TOML_FILE_NAME, line, column, key, &message));
+                }
+            }
+
+        self.set_engines(engines, TOML_FILE_NAME, &mut report);
+        report
+        }
+    fn parse_boolean(value: &str) -> Option<bool> {
+        match value.trim() {
+            "yes" | "true" => Some(true),
+            "no" | "false" => Some(false),
+            _ => None,
+            }
+        }
     fn split_by_colon(line: &str) -> Result<Vec<String>, ()> {
         let mut result: Vec<String>=Vec::new();
 
@@ -342,554 +1430,1218 @@ pub struct SpeechEngineConfiguration {
     pub rate: i32,
     pub volume: i32,
     pub firejailed: bool,
+    pub response_timeout: u64,
+    //Set for alphabets with unicode ranges "detected", which read latin text recognized as their language.
+    pub language_detected: bool,
+    //Line of the configuration file, where the engine was defined, 0 for the default engine.
+    pub line: usize,
     }
 impl SpeechEngineConfiguration {
 
     pub fn new(name: &str) -> SpeechEngineConfiguration {
-        SpeechEngineConfiguration {name: name.to_string(), unicode_ranges: vec![], module: "/usr/lib/speech-dispatcher-modules/sd_espeak-ng".to_string(), arg: "/etc/speech-dispatcher/modules/espeak-ng.conf".to_string(), language: "en".to_string(), voice: "male1".to_string(), punctuation_mode: "some".to_string(), pitch: 10, capitals_pitch: 50, rate: 2, volume: 100, firejailed: false}
+        SpeechEngineConfiguration {name: name.to_string(), unicode_ranges: vec![], module: "/usr/lib/speech-dispatcher-modules/sd_espeak-ng".to_string(), arg: "/etc/speech-dispatcher/modules/espeak-ng.conf".to_string(), language: "en".to_string(), voice: "male1".to_string(), punctuation_mode: "some".to_string(), pitch: 10, capitals_pitch: 50, rate: 2, volume: 100, firejailed: false, response_timeout: 30, language_detected: false, line: 0}
         }
-    pub fn load_from_string(line: &str) -> Result<SpeechEngineConfiguration, &str> {
+    //Parses a line of alphabets_settings.csv. Problems are reported to errors, if they prevent the engine from being used, None is returned, otherwise invalid values are replaced by defaults. Existence of module and its argument is checked only if check_files is set.
+    pub fn load_from_string(line: &str, line_number: usize, check_files: bool, errors: &mut Vec<ConfigError>) -> Option<SpeechEngineConfiguration> {
         let settings: Vec<String>=line.split(',').map(|i| i.to_string()).collect();
 
-        if settings.len()!=12 {
-            return Err("Invalid number of specified options.");
+        //The response timeout column is optional
+        if settings.len()!=12 && settings.len()!=13 {
+            errors.push(ConfigError::new(ALPHABETS_FILE_NAME, line_number, 1, "", &format!("expected 12 or 13 fields separated by commas, found {}", settings.len())));
+            return None;
+            }
+
+        //Columns of fields, so errors can point to them
+        let mut positions: Vec<(usize, usize)>=Vec::new();
+        let mut column=1;
+        for setting in &settings {
+            positions.push((line_number, column));
+            column+=setting.chars().count()+1;
             }
 
+        SpeechEngineConfiguration::load_from_fields(&settings, &positions, ALPHABETS_FILE_NAME, &ALPHABET_FIELD_NAMES, check_files, errors)
+        }
+    //Creates the engine from fields in order of columns of alphabets_settings.csv, the response timeout is optional. Positions contain line and column of each field in the given file, field names are used in error reports.
+    fn load_from_fields(settings: &[String], positions: &[(usize, usize)], file: &str, field_names: &[&str; 13], check_files: bool, errors: &mut Vec<ConfigError>) -> Option<SpeechEngineConfiguration> {
+        let mut report=|field: usize, message: &str| errors.push(ConfigError::new(file, positions[field].0, positions[field].1, field_names[field], message));
+
         //Declare individual variables, some with their default values.
 
-        let (name, module, arg, language, voice, punctuation_mode, firejailed);
+        let (name, module, arg, language, voice);
 
         let unicode_ranges: Vec<u32>;
+        let language_detected=settings[1].trim().to_lowercase()=="detected";
 
+        let mut punctuation_mode="some".to_string();
         let (mut pitch, mut capitals_pitch, mut rate, mut volume)=(10, 50, 2, 100);
+        let mut firejailed=false;
+        let mut response_timeout=30;
 
         //Assign declared variables from received settings
 
         name=settings[0].clone();
 
         //unicode_ranges
-        if &settings[1][..]=="" || &settings[1][..]=="*" {
+        if &settings[1][..]=="" || &settings[1][..]=="*" || language_detected {
             unicode_ranges=Vec::new();
             }
         else {
-            let mut result: Vec<u32>=Vec::new();
-
-            for range in UNICODE_RANGES_MATCHING_REGEX.find_iter(&settings[1]) {
-                let mut errors_found=false;
-                let mut values: Vec<u32>=range.as_str().split('-')
-                .map(|i| {
-                    let i=i[1..].to_lowercase();
-
-                    if let Some(position)=i.find("x") {
-                        match u32::from_str_radix(&i[position+1..], 16) {
-                            Ok(num) => num,
-                            Err(_) => {
-                                errors_found=true;
-                                0
-                                },
-                            }
-                        }
-                    else {
+            match SpeechEngineConfiguration::parse_unicode_ranges(&settings[1]) {
+                Ok(ranges) => unicode_ranges=ranges,
+                Err(message) => {
+                    report(1, &message);
+                    return None;
+                    },
+                };
+            };
 
-                        match i.parse::<u32>() {
-                            Ok(num) => num,
-                            Err(_) => {
-                                errors_found=true;
-                                0
-                                },
-                            }
-                        }
-                    })
-                .collect();
+        module=settings[2].clone();
+        if module.trim().len()==0 {
+            report(2, "no speech module specified");
+            return None;
+            }
+        if check_files {
+            if let Err(message)=check_executable(&module) {
+                report(2, &message);
+                return None;
+                }
+            }
+        arg=settings[3].clone();
+        //Modules can usually run with their default configuration, so a missing argument is just reported
+        if check_files && arg.len()>0 && !Path::new(&arg).exists() {
+            report(3, &format!("file {} doesn't exist", arg));
+            }
+        language=settings[4].clone();
+        voice=settings[5].clone();
+        match &settings[6][..] {
+            "none" | "some" | "all" => punctuation_mode=settings[6].clone(),
+            _ => report(6, &format!("expected none, some or all, found \"{}\", using some", settings[6])),
+            };
 
-                if errors_found {
-                    return Err("Invalid unicode ranges");
-                    }
+        //pitch, capitals_pitch, rate and volume
+        for (field, value) in [(7, &mut pitch), (8, &mut capitals_pitch), (9, &mut rate), (10, &mut volume)].iter_mut() {
+            match settings[*field].parse::<i32>() {
+                Ok(n) if n>=-100 && n<=100 => **value=n,
+                _ => report(*field, &format!("expected a number from -100 to 100, found \"{}\", using {}", settings[*field], value)),
+                };
+            }
+
+        match &settings[11][..] {
+            "yes" | "true" => firejailed=true,
+            "no" | "false" | "" => {},
+            _ => report(11, &format!("expected yes or no, found \"{}\", using no", settings[11])),
+            };
 
-                if values[1]<values[0] {
-                    let temporary=values[0];
-                    values[0]=values[1];
-                    values[1]=temporary;
+        //response_timeout
+        if settings.len()==13 {
+            match settings[12].parse::<u64>() {
+                Ok(n) => response_timeout=n,
+                Err(_) => report(12, &format!("expected a number of seconds, found \"{}\", using {}", settings[12], response_timeout)),
+                };
+            }
+
+        Some(SpeechEngineConfiguration {name, unicode_ranges, module, arg, language, voice, punctuation_mode, pitch, capitals_pitch, rate, volume, firejailed, response_timeout, language_detected, line: positions[0].0})
+        }
+    fn parse_unicode_ranges(s: &str) -> Result<Vec<u32>, String> {
+        let mut result: Vec<u32>=Vec::new();
+
+        for range in UNICODE_RANGES_MATCHING_REGEX.find_iter(s) {
+            let mut errors_found=false;
+            let mut values: Vec<u32>=range.as_str().split('-')
+            .map(|i| {
+                let i=i[1..].to_lowercase();
+
+                if let Some(position)=i.find("x") {
+                    match u32::from_str_radix(&i[position+1..], 16) {
+                        Ok(num) => num,
+                        Err(_) => {
+                            errors_found=true;
+                            0
+                            },
+                        }
                     }
+                else {
+
+                    match i.parse::<u32>() {
+                        Ok(num) => num,
+                        Err(_) => {
+                            errors_found=true;
+                            0
+                            },
+                        }
+                    }
+                })
+            .collect();
 
-                result.push(values[0]);
-                result.push(values[1]);
+            if errors_found {
+                return Err(format!("invalid unicode range {}", range.as_str()));
                 }
 
-            if result.len()==0 {
-                return Err("No unicode ranges specified");
+            if values[1]<values[0] {
+                values.swap(0, 1);
                 }
 
-            unicode_ranges=result;
-            };
-
-        module=settings[2].clone();
-        arg=settings[3].clone();
-        language=settings[4].clone();
-        voice=settings[5].clone();
-        punctuation_mode=match &settings[6][..] {
-            "none" | "some" | "all" => settings[6].clone(),
-            _ => "some".to_string(),
-            };
+            result.push(values[0]);
+            result.push(values[1]);
+            }
 
-        //pitch
-        if let Ok(n)=settings[7].parse::<i32>() {
-            if n>=-100 && n<=100 {
-                pitch=n;
+        //Whatever is not a numeric range, should be a name of a script or block. Names are separated by semicolons, pluses or vertical bars, parts without any letters or digits are just separators of ranges.
+        let names=UNICODE_RANGES_MATCHING_REGEX.replace_all(s, ";");
+        for name in names.split(|ch| ch==';' || ch=='+' || ch=='|') {
+            if !name.chars().any(|ch| ch.is_alphanumeric()) {
+                continue;
                 }
+
+            match unicode_data::resolve_name(name) {
+                Some(ranges) => {
+                    for (start, end) in ranges {
+                        result.push(start);
+                        result.push(end);
+                        }
+                    },
+                None => return Err(format!("unknown script or block name \"{}\"", name.trim())),
+                };
             }
-        //capitals_pitch
-        if let Ok(n)=settings[8].parse::<i32>() {
-            if n>=-100 && n<=100 {
-                capitals_pitch=n;
-                }
+
+        if result.len()==0 {
+            return Err("no unicode ranges specified".to_string());
+            }
+
+        Ok(result)
+        }
+    }
+
+//Loads an alphabet from its table in chinfusor.toml. Missing fields get values of the default engine, so the rest can be validated the same way as alphabets_settings.csv.
+fn load_toml_alphabet(s: &str, name: &str, alphabet: &toml::Value, check_files: bool, errors: &mut Vec<ConfigError>) -> Option<SpeechEngineConfiguration> {
+    let header_position=find_toml_position(s, Some(name), "");
+
+    let alphabet=match alphabet.as_table() {
+        Some(alphabet) => alphabet,
+        None => {
+            errors.push(ConfigError::new(TOML_FILE_NAME, header_position.0, header_position.1, name, "expected a table with settings of the alphabet"));
+            return None;
+            },
+        };
+
+    let default=SpeechEngineConfiguration::new(name);
+    let mut settings: Vec<String>=vec![name.to_string(), "*".to_string(), default.module, default.arg, default.language, default.voice, default.punctuation_mode, default.pitch.to_string(), default.capitals_pitch.to_string(), default.rate.to_string(), default.volume.to_string(), "no".to_string(), default.response_timeout.to_string()];
+    let mut positions: Vec<(usize, usize)>=vec![header_position; 13];
+
+    for (key, value) in alphabet {
+        let position=find_toml_position(s, Some(name), key);
+
+        match TOML_ALPHABET_KEYS.iter().skip(1).position(|alphabet_key| alphabet_key==key) {
+            Some(field) => match toml_value_to_string(value, toml_list_separators(key)) {
+                Ok(value) => {
+                    settings[field+1]=value;
+                    positions[field+1]=position;
+                    },
+                Err(message) => errors.push(ConfigError::new(TOML_FILE_NAME, position.0, position.1, key, &message)),
+                },
+            None => errors.push(ConfigError::new(TOML_FILE_NAME, position.0, 1, key, "unknown setting")),
             };
-        //rate
-        if let Ok(n)=settings[9].parse::<i32>() {
-            if n>=-100 && n<=100 {
-                rate=n;
+        }
+
+    SpeechEngineConfiguration::load_from_fields(&settings, &positions, TOML_FILE_NAME, &TOML_ALPHABET_KEYS, check_files, errors)
+    }
+//TOML values are converted to the form used in alphabets_settings.csv and settings.conf. Arrays are accepted only for keys, which hold lists, their items are joined by the separators the key expects, the first one joins the outermost array.
+fn toml_value_to_string(value: &toml::Value, separators: &[&str]) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Boolean(b) => Ok(if *b { "yes".to_string() } else { "no".to_string() }),
+        toml::Value::Array(items) => {
+            let separator=match separators.first() {
+                Some(separator) => separator,
+                None => return Err("expected a single value, not an array".to_string()),
+                };
+
+            let items=items.iter().map(|item| toml_value_to_string(item, &separators[1..])).collect::<Result<Vec<String>, String>>()?;
+            Ok(items.join(separator))
+            },
+        value => Ok(value.to_string()),
+        }
+    }
+//Separators of items of the settings and alphabet fields, which can be written as arrays in chinfusor.toml.
+fn toml_list_separators(key: &str) -> &'static [&'static str] {
+    match key {
+        "unicode_ranges" => &[";"],
+        "prefer_neighbours" | "minimum_chunk_length" => &[","],
+        "alphabet_groups" => &[",", "+"],
+        _ => &[],
+        }
+    }
+//Finds line and column of the value of the given key, either in the given alphabet table, or among general settings. An empty key finds the header of the table. The toml crate doesn't tell positions of values, so they're searched in the text, if not found, the table header or the beginning of the file is returned.
+fn find_toml_position(s: &str, alphabet: Option<&str>, key: &str) -> (usize, usize) {
+    let mut in_section=alphabet.is_none();
+    let mut section_position=(1, 1);
+
+    for (i, line) in s.lines().enumerate() {
+        let trimmed=line.trim();
+
+        if trimmed.starts_with('[') {
+            let header: String=trimmed.chars().filter(|ch| !ch.is_whitespace() && *ch!='"' && *ch!='\'').collect();
+            in_section=match alphabet {
+                Some(alphabet) => header==format!("[alphabets.{}]", alphabet.chars().filter(|ch| !ch.is_whitespace()).collect::<String>()),
+                None => false,
+                };
+
+            if in_section {
+                section_position=(i+1, 1);
+                if key.len()==0 {
+                    return section_position;
+                    }
                 }
+            continue;
             }
-        //volume
-        if let Ok(n)=settings[10].parse::<i32>() {
-            if n>=-100 && n<=100 {
-                volume=n;
-                }
+
+        if in_section && key.len()>0 && trimmed.starts_with(key) && trimmed[key.len()..].trim_start().starts_with('=') {
+            let value_start=line.find('=').unwrap()+1;
+            let leading_spaces=line[value_start..].chars().take_while(|ch| ch.is_whitespace()).count();
+
+            return (i+1, line[..value_start].chars().count()+leading_spaces+1);
             }
+        }
 
-        firejailed=match &settings[11][..] {
-            "yes" | "true" => true,
-            _ => false,
-            };
+    section_position
+    }
+//Alphabet names, which aren't valid bare TOML keys, need to be quoted.
+fn toml_key(name: &str) -> String {
+    if name.len()>0 && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch=='_' || ch=='-') {
+        name.to_string()
+        }
+    else {
+        toml::Value::String(name.to_string()).to_string()
+        }
+    }
+//Finds the directory with Chinfusor's configuration. A directory given explicitly, either as an argument or by CHINFUSOR_CONFIG_DIR environment variable, is always used. Otherwise, the user's directory in XDG_CONFIG_HOME or ~/.config is preferred, with the system-wide one as a fallback, if the user has no configuration of their own.
+pub fn find_configuration_directory(explicit_directory: Option<String>) -> PathBuf {
+    select_configuration_directory(explicit_directory, |name| std::env::var(name).ok(), Path::new(SYSTEM_CONFIGURATION_DIRECTORY))
+    }
+//Does the work of find_configuration_directory with environment variables read by the given function and the given system-wide directory.
+fn select_configuration_directory<F: Fn(&str) -> Option<String>>(explicit_directory: Option<String>, variable: F, system_directory: &Path) -> PathBuf {
+    let non_empty=|value: String| if value.len()>0 { Some(value) } else { None };
+
+    if let Some(directory)=explicit_directory.or_else(|| variable("CHINFUSOR_CONFIG_DIR")).and_then(non_empty) {
+        return PathBuf::from(directory);
+        }
 
-        Ok(SpeechEngineConfiguration {name, unicode_ranges, module, arg, language, voice, punctuation_mode, pitch, capitals_pitch, rate, volume, firejailed})
+    //XDG base directory specification requires the path to be absolute, relative ones are ignored.
+    let user_directory=variable("XDG_CONFIG_HOME").and_then(non_empty).map(PathBuf::from).filter(|directory| directory.is_absolute())
+    .or_else(|| variable("HOME").and_then(non_empty).map(|home| Path::new(&home).join(".config")))
+    .map(|directory| directory.join("chinfusor"));
+
+    match user_directory {
+        Some(directory) if contains_configuration(&directory) || !contains_configuration(system_directory) => directory,
+        _ => system_directory.to_path_buf(),
+        }
+    }
+fn contains_configuration(directory: &Path) -> bool {
+    [TOML_FILE_NAME, ALPHABETS_FILE_NAME, SETTINGS_FILE_NAME].iter().any(|file| directory.join(file).is_file())
+    }
+//Checks, whether the given program exists and can be executed. Programs given without a path are searched in PATH.
+fn check_executable(program: &str) -> Result<(), String> {
+    let candidates: Vec<PathBuf>=if program.contains('/') {
+        vec![PathBuf::from(program)]
+        }
+    else {
+        std::env::var("PATH").unwrap_or(String::new()).split(':')
+        .map(|directory| Path::new(directory).join(program))
+        .collect()
+        };
+
+    for candidate in &candidates {
+        if let Ok(metadata)=fs::metadata(candidate) {
+            if metadata.is_file() && metadata.permissions().mode() & 0o111!=0 {
+                return Ok(());
+                }
+            else if program.contains('/') {
+                return Err(format!("file {} is not executable", program));
+                }
+            }
         }
+
+    Err(format!("file {} doesn't exist", program))
     }
 
-pub fn run(mut config: Config) {
+//The report of problems found in the configuration is spoken at start, if the user wishes so, and kept up to date when the configuration changes.
+pub fn run(mut config: Config, mut report: ConfigReport, configuration_directory: &Path) {
+    let (events_transmitter, events_receiver)=mpsc::channel::<ChinfusorEvent>();
+
     let mut engines=Vec::new();
     for engine in &config.engines {
-        engines.push(Process::new(&engine.module, &engine.arg, engine.firejailed));
+        engines.push(Process::new(&engine.module, &engine.arg, engine.firejailed, Duration::from_secs(engine.response_timeout), events_transmitter.clone()));
         }
 
     let mut alphabets_scheme=config.generate_alphabets_scheme();
-    let mut audio_settings: Option<AudioSettings>=None;
-    let mut log_level_settings: Option<LogLevelSettings>=None;
+    let mut chunking_options=config.generate_chunking_options();
+    let mut session_settings=SessionSettings {audio: None, log_level: None, speech_parameters: SdSpeechParameters::new()};
 
     let mut currently_speaking_engine=0;
 
     let mut currently_spoken_text: Vec<LanguageChunk>=Vec::new();
     let mut currently_spoken_text_position: usize=0;
     let mut capitalized=false;
-    let mut original_pitch=config.engines[0].pitch;
+    let mut original_pitch=session_settings.speech_parameters.pitch_for(&config.engines[0]);
     let mut speaking=false;
-    let (sd_input_transmitter, sd_input_receiver)=mpsc::channel::<SdInputCommand>();
 
-    let default_language=config.engines[0].language.clone();
-    thread::spawn(move || sd_input_processing_loop(sd_input_transmitter, &default_language));
+    let mut listing_voices=false;
+    //The icon currently being played by an engine, and the process id of player, which plays an icon in case the engine couldn't.
+    let mut currently_played_sound_icon: Option<String>=None;
+    let mut sound_icon_player: Option<SoundIconPlayer>=None;
+
+    let mut report_pending=config.speak_configuration_errors && !report.is_empty();
+    //The report of the configuration in use, the published one contains also problems of a configuration, which couldn't be applied.
+    let mut applied_report=report.clone();
 
-    let (fs_tx, fs_rx)=mpsc::channel();
-    let mut watcher: RecommendedWatcher=Watcher::new_immediate(move |res| fs_tx.send(res).unwrap()).unwrap();
+    let sd_input_transmitter=events_transmitter.clone();
+    thread::spawn(move || sd_input_processing_loop(sd_input_transmitter));
+
+    let fs_transmitter=events_transmitter.clone();
+    let mut watcher: RecommendedWatcher=Watcher::new_immediate(move |res| fs_transmitter.send(ChinfusorEvent::ConfigurationChange(res)).unwrap_or(())).unwrap();
     watcher.configure(NotifyConfig::PreciseEvents(true)).unwrap();
-    watcher.watch(    std::env::var("HOME").unwrap()+"/.config/chinfusor", RecursiveMode::Recursive).unwrap_or_else(|_| ());
+    let mut watched_ancestor: Option<PathBuf>=None;
+    let mut configuration_directory_watched=watch_configuration_directory(&mut watcher, configuration_directory, &mut watched_ancestor);
+
+    //Events, which arrived while waiting for an engine to stop or pause, are processed afterwards in their original order. Configuration changes are applied only when nothing is being spoken.
+    let mut deferred_events: VecDeque<ChinfusorEvent>=VecDeque::new();
+    //Configuration is reloaded, once its files didn't change for a while.
+    let mut configuration_deadline: Option<Instant>=None;
+    let mut configuration_reload_pending=false;
 
     loop {
-        //Catch input from Speech dispatcher
-        let sd_input=if speaking {
-            match sd_input_receiver.try_recv() {
-                Ok(result) => Some(result),
-                _ => None,
-                }
-            } else {
-            match sd_input_receiver.recv() {
-                Ok(result) => Some(result),
-                _ => None,
+        //If nothing is currently being spoken and speech-dispatcher doesn't wait for voices, apply changes of the configuration. Engines don't need to have audio settings yet, they get them together with the others.
+        if configuration_reload_pending && !speaking && !listing_voices {
+            configuration_reload_pending=false;
+
+            //Engines are matched by names of their alphabets, so we need to remember the old ones before loading.
+            let old_names: Vec<String>=config.engines.iter().map(|engine| engine.name.clone()).collect();
+
+            let (new_config, new_report)=Config::load_from_directory(configuration_directory);
+            let file=if configuration_directory.join(TOML_FILE_NAME).exists() { TOML_FILE_NAME } else { ALPHABETS_FILE_NAME };
+
+            //A configuration, which lost some alphabets, would replace their working engines by the default one or by nothing, so it's not applied at all.
+            let result=if new_report.alphabets_dropped {
+                Err(ConfigError::new(file, 0, 0, "", "some alphabets couldn't be loaded, the previous configuration is kept"))
                 }
-            };
+            else {
+                update_engines(&mut engines, &old_names, &new_config, &session_settings, &events_receiver, &mut deferred_events, &events_transmitter)
+                .map_err(|(id, message)| ConfigError::new(file, new_config.engines[id].line, 1, "module", &format!("{}, the previous configuration is kept", message.trim_end_matches('.'))))
+                };
+
+            match result {
+                Ok(()) => {
+                    config=new_config;
+                    applied_report=new_report;
+                    report=applied_report.clone();
+
+                    //Ranges are remapped without restarting anything.
+                    alphabets_scheme=config.generate_alphabets_scheme();
+                    chunking_options=config.generate_chunking_options();
+                    },
+                //The running engines are untouched, so the previous configuration stays in use together with its problems. The new ones are reported first.
+                Err(error) => {
+                    report=ConfigReport::new();
+                    report.errors.push(error);
+                    report.errors.extend(new_report.errors.into_iter().filter(|error| !applied_report.errors.contains(error)));
+                    report.extend(applied_report.clone());
+
+                    if new_config.speak_configuration_errors {
+                        report_pending=true;
+                        }
+                    },
+                };
 
-        //If nothing is currently being spoken, check, if user didn't change configuration
-        if !speaking {
-            if let Ok(Ok(event))=fs_rx.try_recv() {
-                if event.paths.len()==1 {
-                    if let EventKind::Modify(_)=&event.kind {
-                        let path=event.paths[0].to_str().unwrap();
+            update_configuration_report(&report, configuration_directory);
+            }
 
-                        if path.ends_with("alphabets_settings.csv") {
-                            //We need to load again the configuration, recreate list of engines, initialize, set audio, loglevel and properties for each of them.
+        //Once the engines can speak, the user can be told about problems in the configuration.
+        if report_pending && session_settings.audio.is_some() && !speaking && !listing_voices {
+            let summary=escape_ssml(&report.generate_summary());
+            engines[0].speak(&Request::Speak(format!("<speak>{}</speak>", summary)));
+            report_pending=false;
+            }
 
-                            if let Some(audio_settings)=&audio_settings {
-                                config.load_alphabets_from_file(path);
+        //Speech-dispatcher waits for the list of voices, until all engines have answered.
+        if listing_voices && engines.iter().all(|engine| engine.voices().is_some()) {
+            respond(&generate_voice_list(&config, &engines));
+            listing_voices=false;
+            }
 
-                                //First, deinitialize currently running engines;
+        let event=if let Some(event)=deferred_events.pop_front() {
+            event
+            } else {
+            match next_event(&events_receiver, &mut engines, configuration_deadline) {
+                Some(event) => event,
+                None => break,
+                }
+            };
 
-                                for engine in &mut engines {
-                                    engine.write_line("QUIT");
-                                    engine.wait_for_exit();
-                                    }
+        match event {
+            ChinfusorEvent::ConfigurationChange(Ok(event)) => {
+                if let EventKind::Access(_)=event.kind {
+                    continue;
+                    }
 
-                                //Then, start newones
+                //If the directory didn't exist, something in its ancestor has changed, so it might have been created.
+                let mut directory_created=false;
+                if !configuration_directory_watched {
+                    configuration_directory_watched=watch_configuration_directory(&mut watcher, configuration_directory, &mut watched_ancestor);
+                    directory_created=configuration_directory_watched;
+                    }
 
-                                engines=Vec::new();
-                                for engine in &config.engines {
-                                    engines.push(Process::new(&engine.module, &engine.arg, engine.firejailed));
-                                    }
+                //Files are created, written, renamed and removed in various ways, so any change of any of them counts.
+                if directory_created || event.paths.iter().any(|path| is_configuration_file(path)) {
+                    configuration_deadline=Some(Instant::now()+CONFIGURATION_CHANGE_DEBOUNCE);
+                    }
+                },
+            ChinfusorEvent::ConfigurationSettled => {
+                configuration_deadline=None;
+                configuration_reload_pending=true;
+                },
+            ChinfusorEvent::ConfigurationChange(Err(_)) => {},
+            ChinfusorEvent::SoundIconPlayed(pid) => {
+                if sound_icon_player.as_ref().map(|player| player.pid)==Some(pid) {
+                    sound_icon_player=None;
+                    speaking=false;
+                    respond(&Response::End);
+                    }
+                },
+            ChinfusorEvent::EngineStdoutClosed(instance) => {
+                for engine in engines.iter_mut() {
+                    engine.handle_stdout_closed(instance);
+                    }
+                },
+            //Check whether currently speaking module has finished and update things accordingly.
+            ChinfusorEvent::EngineReply(instance, reply) => {
+                let engine=match engines.iter().position(|engine| engine.instance()==instance) {
+                    Some(engine) => engine,
+                    None => continue,
+                    };
+                engines[engine].accept_reply(&reply);
+
+                if !speaking || engine!=currently_speaking_engine {
+                    continue;
+                    }
 
-                                alphabets_scheme=config.generate_alphabets_scheme();
+                if let Response::IndexMark(name)=&reply {
+                    report_index_mark(name);
+                    }
+                else if let Response::End=&reply {
+                    currently_spoken_text_position+=1;
 
-                                //Now, we need to initialize and configure each of them
+                    let sound_icon=currently_played_sound_icon.take();
 
-                                for (id, engine) in engines.iter_mut().enumerate() {
-                                    engine.write_line("INIT");
+                    //If the module failed, the rest of the utterance is dropped, so speech-dispatcher can carry on.
+                    if engines[currently_speaking_engine].take_failure() {
+                        currently_spoken_text_position=currently_spoken_text.len();
 
-                                    engine.write(&audio_settings.generate_sd_command());
-                                    engine.write(&SpeechSettings::generate_sd_command_from_engine_configuration(&config.engines[id]));
+                        //If the module can't play the sound icon, it's played from a file, or at least its name is spoken.
+                        if let Some(sound_icon)=sound_icon {
+                            if let Some(file)=config.get_sound_icon_file(&sound_icon) {
+                                sound_icon_player=play_sound_file(&config.sound_icon_player, &file, &events_transmitter);
 
-                                    if let Some(log_level_settings)=&log_level_settings {
-                                        engine.write(&log_level_settings.generate_sd_command());
-                                        }
+                                if sound_icon_player.is_some() {
+                                    continue;
                                     }
-
                                 }
-                            }
-                        else if path.ends_with("settings.conf") {
-                            config.load_configuration_from_file(path);
+
+                            engines[currently_speaking_engine].speak(&Request::Speak(format!("<speak>{}</speak>", escape_ssml(&sound_icon))));
+                            continue;
                             }
                         }
-                    }
-                }
-            }
-
-        //Check the input from speech-dispatcher
-        if let Some(sd_command)=sd_input {
 
-            match sd_command {
-                SdInputCommand::Init => {
-                    for engine in engines.iter_mut() {
-                        engine.write_line("INIT");
-                        }
-                    },
-                SdInputCommand::Audio(settings) => {
-                    for (id, engine) in engines.iter_mut().enumerate() {
-                        engine.write(&settings.generate_sd_command());
-                        engine.write(&SpeechSettings::generate_sd_command_from_engine_configuration(&config.engines[id]));
+                    if let Some(engine)=speak_next_chunk(&currently_spoken_text, &mut currently_spoken_text_position, &mut engines) {
+                        currently_speaking_engine=engine;
+                        continue;
                         }
 
-                    audio_settings=Some(settings);
-                    },
-                SdInputCommand::Set(_settings) => {
-                    //currently_speaking_engine.write(&settings.generate_sd_command());
-                    //another_engine.write(&settings.generate_sd_command());
-                    },
-                SdInputCommand::LogLevel(settings) => {
-                    for engine in engines.iter_mut() {
-                        engine.write(&settings.generate_sd_command());
+                    speaking=false;
+                    if capitalized {
+                        engines[currently_speaking_engine].write(&SpeechSettings::generate_sd_command_from_pitch(original_pitch));
+                        capitalized=false;
                         }
 
-                    log_level_settings=Some(settings);
-                    },
-                SdInputCommand::Speak(text) => {
-                    if !speaking {
+                    respond(&Response::End);
+                    }
+                },
+            //Check the input from speech-dispatcher
+            ChinfusorEvent::SdInput(sd_command) => {
+
+                match sd_command {
+                    Request::Init => {
+                        //Engines started by a reload are already initialized.
+                        for engine in engines.iter_mut().filter(|engine| !engine.is_initialized()) {
+                            engine.write(&Request::Init);
+                            }
+                        },
+                    Request::Audio(lines) => {
+                        let settings=AudioSettings::new(lines);
+                        for (id, engine) in engines.iter_mut().enumerate() {
+                            engine.write(&settings.generate_sd_command());
+                            engine.write(&SpeechSettings::generate_sd_command_from_engine_configuration(&config.engines[id], &session_settings.speech_parameters));
+                            }
 
-                        currently_spoken_text=text_processor::parse_text(&text.replace("<speak>", "").replace("</speak>", ""), &alphabets_scheme, &config.punctuation_characters, true);
+                        session_settings.audio=Some(settings);
+                        },
+                    Request::Set(lines) => {
+                        session_settings.speech_parameters.update(&SpeechSettings::new(lines), &config);
 
-                        if currently_spoken_text.len()>0 {
-                            currently_spoken_text_position=0;
-                            currently_speaking_engine=currently_spoken_text[0].0;
+                        for (id, engine) in engines.iter_mut().enumerate() {
+                            engine.write(&SpeechSettings::generate_sd_command_from_engine_configuration(&config.engines[id], &session_settings.speech_parameters));
+                            }
+                        },
+                    Request::LogLevel(lines) => {
+                        let settings=LogLevelSettings::new(lines);
+                        for engine in engines.iter_mut() {
+                            engine.write(&settings.generate_sd_command());
+                            }
 
-                            let text=&currently_spoken_text[0].1;
+                        session_settings.log_level=Some(settings);
+                        },
+                    Request::Speak(text) => {
+                        if !speaking {
 
-                            engines[currently_speaking_engine].write(&format!("SPEAK\n<speak>{}</speak>\n.\n", text));
+                            currently_spoken_text=text_processor::parse_text(&text, &alphabets_scheme, &config.punctuation_characters, &chunking_options, true);
 
-                            speaking=true;
-                            println!("701 BEGIN");
-                            engines[currently_speaking_engine].activate_asynchronous_reading_until_sd_end_signal();
-                            }
-                        else {
-                            println!("701 BEGIN");
-                            println!("702 END");
+                            currently_spoken_text_position=0;
+                            respond(&Response::Begin);
+
+                            if let Some(engine)=speak_next_chunk(&currently_spoken_text, &mut currently_spoken_text_position, &mut engines) {
+                                currently_speaking_engine=engine;
+                                speaking=true;
+                                }
+                            else {
+                                respond(&Response::End);
+                                }
                             }
-                        }
-                    },
-                SdInputCommand::Key(text) => {
-                    currently_speaking_engine=0;
+                        },
+                    Request::Key(text) => {
+                        currently_speaking_engine=0;
+                        currently_spoken_text.clear();
+                        currently_spoken_text_position=0;
 
-                    engines[currently_speaking_engine].write(&format!("KEY\n{}\n.\n", text));
+                        speaking=true;
 
-                    speaking=true;
+                        respond(&Response::Begin);
+                        engines[currently_speaking_engine].speak(&Request::Key(text));
+                        },
+                    Request::SoundIcon(icon) => {
+                        currently_speaking_engine=config.get_sound_icon_engine_id();
+                        currently_spoken_text.clear();
+                        currently_spoken_text_position=0;
+                        currently_played_sound_icon=Some(icon.clone());
 
-                    println!("701 BEGIN");
-                    engines[currently_speaking_engine].activate_asynchronous_reading_until_sd_end_signal();
-                    }
-                SdInputCommand::Char(ch) => {
-                    currently_speaking_engine=text_processor::identify_character(ch, &alphabets_scheme);
-                    original_pitch=config.engines[currently_speaking_engine].pitch;
-                    let capitalized_pitch=config.engines[currently_speaking_engine].capitals_pitch;
+                        speaking=true;
 
-                    if ch.is_uppercase() {
-                        engines[currently_speaking_engine].write(&SpeechSettings::generate_sd_command_from_pitch(capitalized_pitch));
-                        capitalized=true;
-                        }
+                        respond(&Response::Begin);
+                        engines[currently_speaking_engine].speak(&Request::SoundIcon(icon));
+                        },
+                    Request::ListVoices => {
+                        for engine in engines.iter_mut() {
+                            engine.request_voices();
+                            }
 
-                    engines[currently_speaking_engine].write(&format!("CHAR\n{}\n.\n", ch));
+                        listing_voices=true;
+                        },
+                    Request::Char(character) => {
+                        let ch=character.chars().next().unwrap_or(' ');
+                        currently_speaking_engine=text_processor::identify_character(ch, &alphabets_scheme);
+                        currently_spoken_text.clear();
+                        currently_spoken_text_position=0;
+                        original_pitch=session_settings.speech_parameters.pitch_for(&config.engines[currently_speaking_engine]);
+                        let capitalized_pitch=session_settings.speech_parameters.capitals_pitch_for(&config.engines[currently_speaking_engine]);
+
+                        if ch.is_uppercase() {
+                            engines[currently_speaking_engine].write(&SpeechSettings::generate_sd_command_from_pitch(capitalized_pitch));
+                            capitalized=true;
+                            }
 
-                    speaking=true;
+                        speaking=true;
 
-                    println!("701 BEGIN");
-                    engines[currently_speaking_engine].activate_asynchronous_reading_until_sd_end_signal();
-                    },
-                SdInputCommand::Pause => {
-                    if speaking {
-                        engines[currently_speaking_engine].write_line("PAUSE");
-                        loop {
-                            while let Some(line)=engines[currently_speaking_engine].read_line() {
-
-                                if line=="704 PAUSE" {
-                                    speaking=false;
-                                    println!("704 PAUSE");
-                                    break;
-                                    }
-                                else if line=="702 END".to_string() {
-                                    speaking=false;
-                                    println!("702 END");
-                                    break;
-                                    }
-                                else if line.starts_with("700") {
-                                    println!("{}", line);
-                                    }
-                                }
-                            if !speaking {
-                                break;
-                                }
-                            std::thread::sleep(std::time::Duration::from_millis(1));
+                        respond(&Response::Begin);
+                        engines[currently_speaking_engine].speak(&Request::Char(character));
+                        },
+                    Request::Pause | Request::Stop => {
+                        let signal=if let Request::Pause=sd_command {
+                            Response::Pause
+                            } else {
+                            Response::Stop
+                            };
+
+                        if let Some(player)=sound_icon_player.take() {
+                            stop_sound_file(&player);
+
+                            speaking=false;
+                            respond(&signal);
                             }
+                        else if speaking {
+                            currently_played_sound_icon=None;
 
-                        if capitalized {
-                            engines[currently_speaking_engine].write(&SpeechSettings::generate_sd_command_from_pitch(original_pitch));
-                            capitalized=false;
-                            }
+                            engines[currently_speaking_engine].write(&sd_command);
+                            wait_for_interruption(&events_receiver, &mut deferred_events, &mut engines, currently_speaking_engine, signal);
 
-                        continue;
-                        }
-                    },
-                SdInputCommand::Stop => {
-                    if speaking {
-                        engines[currently_speaking_engine].write_line("STOP");
-                        loop {
-                            while let Some(line)=engines[currently_speaking_engine].read_line() {
-
-                                if line=="703 STOP" {
-                                    speaking=false;
-                                    println!("703 STOP");
-                                    break;
-                                    }
-                                else if line=="702 END".to_string() {
-                                    speaking=false;
-                                    println!("702 END");
-                                    break;
-                                    }
-                                else if line.starts_with("700") {
-                                    println!("{}", line);
-                                    }
-                                }
-                            if !speaking {
-                                break;
+                            speaking=false;
+                            currently_spoken_text_position=currently_spoken_text.len();
+
+                            if capitalized {
+                                engines[currently_speaking_engine].write(&SpeechSettings::generate_sd_command_from_pitch(original_pitch));
+                                capitalized=false;
                                 }
-                            std::thread::sleep(std::time::Duration::from_millis(1));
+                            }
+                        },
+                    Request::Quit => {
+                        if let Some(player)=sound_icon_player.take() {
+                            stop_sound_file(&player);
                             }
 
-                        if capitalized {
-                            engines[currently_speaking_engine].write(&SpeechSettings::generate_sd_command_from_pitch(original_pitch));
-                            capitalized=false;
+                        for engine in engines.iter_mut() {
+                            engine.quit();
                             }
 
-                        continue;
-                        }
-                    },
-                SdInputCommand::Quit => {
+                        respond(&Response::Ok(210, Vec::new(), "OK QUIT".to_string()));
+
+                        break;
+                        },
+                    };
+                },
+            };
+        }
+
+    for engine in engines.iter_mut() {
+        engine.wait_for_exit();
+        }
+    }
+
+//Blocks until the next event arrives. Response timeouts of engines, which are expected to respond, are watched at the same time, as well as the given deadline of a configuration change, after which ConfigurationSettled is returned.
+fn next_event(events_receiver: &mpsc::Receiver<ChinfusorEvent>, engines: &mut [Process], configuration_deadline: Option<Instant>) -> Option<ChinfusorEvent> {
+    loop {
+        if configuration_deadline.map(|deadline| deadline<=Instant::now()).unwrap_or(false) {
+            return Some(ChinfusorEvent::ConfigurationSettled);
+            }
+
+        let configuration_timeout=configuration_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let timeout=engines.iter().filter_map(|engine| engine.time_until_timeout()).chain(configuration_timeout).min();
+
+        match timeout {
+            Some(timeout) => match events_receiver.recv_timeout(timeout) {
+                Ok(event) => return Some(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
                     for engine in engines.iter_mut() {
-                        engine.write_line("QUIT");
+                        engine.check_timeout();
                         }
+                    },
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                },
+            None => return events_receiver.recv().ok(),
+            };
+        }
+    }
+//Brings running engines in line with the changed configuration. Engines are matched by names of their alphabets, those running the same module with the same argument and sandboxing are kept and just get new settings, the others are started again. Engines of removed alphabets are quit.
+//New engines are started and initialized first. If any of them fails, the running engines are left untouched, and id of the failed engine in the configuration is returned with the error.
+fn update_engines(engines: &mut Vec<Process>, old_names: &[String], config: &Config, session_settings: &SessionSettings, events_receiver: &mpsc::Receiver<ChinfusorEvent>, deferred_events: &mut VecDeque<ChinfusorEvent>, events_transmitter: &mpsc::Sender<ChinfusorEvent>) -> Result<(), (usize, String)> {
+    //For each engine of the configuration, the running engine, which can be kept, if there's any.
+    let mut kept_engines: Vec<Option<usize>>=Vec::new();
+    for engine_configuration in &config.engines {
+        let kept_engine=old_names.iter().enumerate()
+        .position(|(id, name)| *name==engine_configuration.name && id<engines.len() && engines[id].runs(engine_configuration) && !kept_engines.contains(&Some(id)));
+
+        kept_engines.push(kept_engine);
+        }
+
+    let mut new_engines: Vec<Process>=Vec::new();
+    let mut new_engine_ids: Vec<usize>=Vec::new();
+    for (id, engine_configuration) in config.engines.iter().enumerate() {
+        if kept_engines[id].is_some() {
+            continue;
+            }
 
-                    println!("210 OK QUIT");
+        match Process::try_new(&engine_configuration.module, &engine_configuration.arg, engine_configuration.firejailed, Duration::from_secs(engine_configuration.response_timeout), events_transmitter.clone()) {
+            Ok(mut engine) => {
+                engine.initialize();
+                new_engines.push(engine);
+                new_engine_ids.push(id);
+                },
+            Err(e) => {
+                for engine in new_engines.iter_mut() {
+                    engine.kill();
+                    }
+                return Err((id, e));
+                },
+            };
+        }
 
-                    break;
-                    },
-                };
+    if let Err(position)=wait_for_initialization(events_receiver, deferred_events, &mut new_engines) {
+        let message=format!("{} failed to initialize", config.engines[new_engine_ids[position]].module);
+        for engine in new_engines.iter_mut() {
+            engine.kill();
             }
+        return Err((new_engine_ids[position], message));
+        }
 
-        //Check whether currently speaking module has finished and update things accordingly.
-        if speaking {
-            while let Some(line)=engines[currently_speaking_engine].read_line() {
-                //println!("{}", line);
-                if line.starts_with("700") {
-                    println!("{}", line);
+    //All engines are ready, so they can be swapped.
+    let mut old_engines: Vec<Option<Process>>=engines.drain(..).map(Some).collect();
+    let mut new_engines=new_engines.into_iter();
+
+    for (id, engine_configuration) in config.engines.iter().enumerate() {
+        let engine=match kept_engines[id] {
+            Some(old_id) => {
+                let mut engine=old_engines[old_id].take().unwrap();
+                engine.set_response_timeout(Duration::from_secs(engine_configuration.response_timeout));
+                engine.write(&SpeechSettings::generate_sd_command_from_engine_configuration(engine_configuration, &session_settings.speech_parameters));
+                engine
+                },
+            None => {
+                //New engines are brought to the state of the others, whatever they didn't get yet, they'll get with them.
+                let mut engine=new_engines.next().unwrap();
+                if let Some(audio_settings)=&session_settings.audio {
+                    engine.write(&audio_settings.generate_sd_command());
                     }
-                else if line=="702 END".to_string() {
-                    currently_spoken_text_position+=1;
-                    if currently_spoken_text_position>=currently_spoken_text.len() {
-                        speaking=false;
-                        if capitalized {
-                            engines[currently_speaking_engine].write(&SpeechSettings::generate_sd_command_from_pitch(original_pitch));
-                            capitalized=false;
-                            }
+                engine.write(&SpeechSettings::generate_sd_command_from_engine_configuration(engine_configuration, &session_settings.speech_parameters));
+                if let Some(log_level_settings)=&session_settings.log_level {
+                    engine.write(&log_level_settings.generate_sd_command());
+                    }
+                engine
+                },
+            };
+
+        engines.push(engine);
+        }
 
-                        println!("702 END");
+    for engine in old_engines.iter_mut().flatten() {
+        engine.quit();
+        engine.wait_for_exit();
+        }
 
-                        continue;
-                        }
+    Ok(())
+    }
+//Waits until all the given engines answer INIT. Events unrelated to them are deferred. Returns position of an engine, which failed.
+fn wait_for_initialization(events_receiver: &mpsc::Receiver<ChinfusorEvent>, deferred_events: &mut VecDeque<ChinfusorEvent>, engines: &mut Vec<Process>) -> Result<(), usize> {
+    loop {
+        if let Some(position)=engines.iter().position(|engine| engine.initialization_result()==Some(false)) {
+            return Err(position);
+            }
+        if engines.iter().all(|engine| engine.initialization_result().is_some()) {
+            return Ok(());
+            }
+
+        let event=match next_event(events_receiver, engines, None) {
+            Some(event) => event,
+            None => return Err(0),
+            };
 
-                    currently_speaking_engine=currently_spoken_text[currently_spoken_text_position].0;
+        match event {
+            ChinfusorEvent::EngineReply(instance, reply) if engines.iter().any(|engine| engine.instance()==instance) => {
+                for engine in engines.iter_mut().filter(|engine| engine.instance()==instance) {
+                    engine.accept_reply(&reply);
+                    }
+                },
+            ChinfusorEvent::EngineStdoutClosed(instance) if engines.iter().any(|engine| engine.instance()==instance) => {
+                for engine in engines.iter_mut() {
+                    engine.handle_stdout_closed(instance);
+                    }
+                },
+            event => deferred_events.push_back(event),
+            };
+        }
+    }
+//Waits until the speaking engine confirms its interruption, or finishes on its own. Events unrelated to the engine are deferred, so they can be processed afterwards.
+fn wait_for_interruption(events_receiver: &mpsc::Receiver<ChinfusorEvent>, deferred_events: &mut VecDeque<ChinfusorEvent>, engines: &mut Vec<Process>, speaking_engine: usize, signal: Response) {
+    while let Some(event)=next_event(events_receiver, engines, None) {
+        match event {
+            ChinfusorEvent::EngineReply(instance, reply) => {
+                let engine=match engines.iter().position(|engine| engine.instance()==instance) {
+                    Some(engine) => engine,
+                    None => continue,
+                    };
+                engines[engine].accept_reply(&reply);
 
-                    let text=&currently_spoken_text[currently_spoken_text_position].1;
+                if engine!=speaking_engine {
+                    continue;
+                    }
 
-                    engines[currently_speaking_engine].write(&format!("SPEAK\n<speak>{}</speak>\n.\n", text));
-                    engines[currently_speaking_engine].activate_asynchronous_reading_until_sd_end_signal()
+                match reply {
+                    Response::IndexMark(name) => report_index_mark(&name),
+                    Response::End => {
+                        respond(&Response::End);
+                        return;
+                        },
+                    reply if reply==signal => {
+                        respond(&signal);
+                        return;
+                        },
+                    _ => {},
+                    };
+                },
+            ChinfusorEvent::EngineStdoutClosed(instance) => {
+                for engine in engines.iter_mut() {
+                    engine.handle_stdout_closed(instance);
                     }
+                },
+            event => deferred_events.push_back(event),
+            };
+        }
+    }
 
-                }
-            std::thread::sleep(std::time::Duration::from_millis(1));
+//Logs the report and writes it into the configuration directory.
+fn update_configuration_report(report: &ConfigReport, configuration_directory: &Path) {
+    report.log();
+    report.write_to_file(&configuration_directory.join(CONFIGURATION_REPORT_FILE_NAME).to_string_lossy());
+    }
+//Watches the configuration directory. If it doesn't exist yet, its nearest existing ancestor is watched instead, so we know, when it's created. Returns, whether the directory itself is watched.
+fn watch_configuration_directory(watcher: &mut RecommendedWatcher, directory: &Path, watched_ancestor: &mut Option<PathBuf>) -> bool {
+    if let Some(ancestor)=watched_ancestor.take() {
+        watcher.unwatch(&ancestor).unwrap_or(());
+        }
+
+    if directory.is_dir() {
+        return watcher.watch(directory, RecursiveMode::Recursive).is_ok();
+        }
+
+    if let Some(ancestor)=directory.ancestors().skip(1).find(|ancestor| ancestor.is_dir()) {
+        if watcher.watch(ancestor, RecursiveMode::NonRecursive).is_ok() {
+            *watched_ancestor=Some(ancestor.to_path_buf());
             }
         }
 
-    for engine in engines.iter_mut() {
-        engine.wait_for_exit();
+    false
+    }
+fn is_configuration_file(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name==TOML_FILE_NAME || name==ALPHABETS_FILE_NAME || name==SETTINGS_FILE_NAME,
+        None => false,
         }
     }
+//Writes a message to Chinfusor's log. Speech-dispatcher stores standard error output of its modules in their log files.
+pub fn log(message: &str) {
+    eprintln!("Chinfusor: {}", message);
+    }
 
-fn sd_input_processing_loop(tx: mpsc::Sender<SdInputCommand>, default_language: &str) {
-    let stdin=std::io::stdin();
-    loop {
-        let mut input=String::new();
-        stdin.read_line(&mut input).unwrap();
+//Writes a reply or an event for speech-dispatcher. If it's gone, there's nobody to tell anything anyway.
+fn respond(response: &Response) {
+    let stdout=std::io::stdout();
+    let mut stdout=stdout.lock();
 
-        match input.trim() {
-            "INIT" => {
-                println!("299-Chinfusor: Initialized successfully.");
-                println!("299 OK LOADED SUCCESSFULLY");
-                tx.send(SdInputCommand::Init).unwrap();
-                },
-            "AUDIO" => {
-                let mut lines: Vec<String>=Vec::new();
-                println!("207 OK RECEIVING AUDIO SETTINGS");
+    stdout.write_all(response.encode().as_bytes()).unwrap_or(());
+    stdout.flush().unwrap_or(());
+    }
+//Plays the file in background. When the playback ends, SoundIconPlayed event with process id of the player is sent.
+fn play_sound_file(player: &str, file_path: &str, events_transmitter: &mpsc::Sender<ChinfusorEvent>) -> Option<SoundIconPlayer> {
+    //Output of the player must not get mixed with our replies to speech-dispatcher.
+    let process=match Exec::cmd(player).arg(file_path).stdin(NullFile).stdout(NullFile).popen() {
+        Ok(process) => process,
+        Err(_) => {
+            log(&format!("Unable to start {} to play {}.", player, file_path));
+            return None;
+            },
+        };
+    let pid=process.pid()?;
+    let process=Arc::new(Mutex::new(process));
+
+    let events_transmitter=events_transmitter.clone();
+    let waited_process=Arc::clone(&process);
+    thread::spawn(move || {
+        //The player is only waited for here without being reaped, it's reaped with the lock held, so it can't be stopped after its pid was released.
+        let mut info: libc::siginfo_t=unsafe { std::mem::zeroed() };
+        while unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT) }==-1 {
+            if std::io::Error::last_os_error().kind()!=std::io::ErrorKind::Interrupted {
+                break;
+                }
+            }
 
-                loop {
-                    let mut input=String::new();
-                    stdin.read_line(&mut input).unwrap();
-                    input=input.trim().to_string();
+        waited_process.lock().unwrap().wait().ok();
+        events_transmitter.send(ChinfusorEvent::SoundIconPlayed(pid)).unwrap_or(());
+        });
 
-                    if input=="." {
-                        break;
-                        }
+    Some(SoundIconPlayer { pid, process })
+    }
+//Popen doesn't signal a process, which was already reaped.
+fn stop_sound_file(player: &SoundIconPlayer) {
+    player.process.lock().unwrap().terminate().ok();
+    }
+//Sound icons may contain anything, while modules are given SSML.
+fn escape_ssml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+//Voices of all engines are offered to speech-dispatcher, each labelled with the alphabet it belongs to.
+fn generate_voice_list(config: &Config, engines: &[Process]) -> Response {
+    let mut voices=Vec::new();
+
+    for (id, engine) in engines.iter().enumerate() {
+        if let Some(engine_voices)=engine.voices() {
+            for voice in engine_voices {
+                let (name, the_rest)=match voice.find('\t') {
+                    Some(position) => (&voice[..position], &voice[position..]),
+                    None => (&voice[..], ""),
+                    };
 
-                    lines.push(input);
-                    }
+                voices.push(format!("{} ({}){}", name, config.engines[id].name, the_rest));
+                }
+            }
+        }
 
-                println!("203 OK AUDIO INITIALIZED");
-                tx.send(SdInputCommand::Audio(AudioSettings::new(lines))).unwrap();
-                },
-            "LOGLEVEL" => {
-                let mut lines: Vec<String>=Vec::new();
-                println!("207 OK RECEIVING LOGLEVEL SETTINGS");
+    if voices.len()==0 {
+        voices.push(format!("Default\t{}\tnone", config.engines[0].language));
+        }
 
-                loop {
-                    let mut input=String::new();
-                    stdin.read_line(&mut input).unwrap();
-                    input=input.trim().to_string();
+    Response::Ok(200, voices, "OK VOICE LIST SENT".to_string())
+    }
+//Splits a voice name from the list of voices into the original name and the alphabet.
+fn split_voice_label(label: &str) -> Option<(&str, &str)> {
+    if !label.ends_with(')') {
+        return None;
+        }
 
-                    if input=="." {
-                        break;
-                        }
+    let position=label.rfind(" (")?;
+    Some((&label[..position], &label[position+2..label.len()-1]))
+    }
+fn report_index_mark(name: &str) {
+    respond(&Response::IndexMark(name.to_string()));
+    }
+//Starts speaking the first chunk from the given position, which contains some text. Index marks preceding the text are reported right away, so they're not lost at chunk boundaries. Returns id of the engine, which was activated, or None if there's nothing more to speak.
+fn speak_next_chunk(chunks: &[LanguageChunk], position: &mut usize, engines: &mut [Process]) -> Option<usize> {
+    while *position<chunks.len() {
+        let LanguageChunk(engine, text)=&chunks[*position];
+        let (marks, text)=text_processor::split_leading_marks(text);
+
+        for mark in marks {
+            report_index_mark(&mark);
+            }
 
-                    lines.push(input);
-                    }
+        if text.trim().len()>0 {
+            engines[*engine].speak(&Request::Speak(format!("<speak>{}</speak>", text)));
 
-                println!("203 OK LOGLEVEL SET");
-                tx.send(SdInputCommand::LogLevel(LogLevelSettings::new(lines))).unwrap();
-                },
-            "SET" => {
-                let mut lines: Vec<String>=Vec::new();
-                println!("203 OK RECEIVING SETTINGS");
+            return Some(*engine);
+            }
 
-                loop {
-                    let mut input=String::new();
-                    stdin.read_line(&mut input).unwrap();
-                    input=input.trim().to_string();
+        *position+=1;
+        }
 
-                    if input=="." {
-                        break;
-                        }
+    None
+    }
+
+fn sd_input_processing_loop(tx: mpsc::Sender<ChinfusorEvent>) {
+    let stdin=std::io::stdin();
+    let mut parser=RequestParser::new();
 
-                    lines.push(input);
+    loop {
+        let mut line=String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                if let Err(e)=parser.finish() {
+                    log(&e.to_string());
                     }
 
-                println!("203 OK SETTINGS RECEIVED");
-                tx.send(SdInputCommand::Set(SpeechSettings::new(lines))).unwrap();
+                //Speech-dispatcher has closed our input, so it won't ask for anything anymore.
+                tx.send(ChinfusorEvent::SdInput(Request::Quit)).unwrap_or(());
+                return;
                 },
-            "SPEAK" => {
-                let mut text=String::new();
-                println!("202 OK RECEIVING MESSAGE");
+            Ok(_) => {},
+            };
 
-                loop {
-                    let mut line=String::new();
-                    stdin.read_line(&mut line).unwrap();
+        let request=match parser.parse_line(&line) {
+            Ok(Some(ParsedRequest::Complete(request))) => request,
+            Ok(Some(ParsedRequest::DataRequested(name))) => {
+                if let Some(response)=Response::data_requested(name) {
+                    respond(&response);
+                    }
+                continue;
+                },
+            Ok(None) => continue,
+            Err(e) => {
+                log(&e.to_string());
+                respond(&e.response());
+                continue;
+                },
+            };
 
-                    match &line[..] {
-                        ".\n" => {
-                            break;
-                            }
-                        _ => {
-                            text+=&line;
-                            }
-                        };
+        if let Some(response)=Response::data_received(&request) {
+            respond(&response);
+            }
 
-                    }
+        if let Request::Init=request {
+            respond(&Response::Ok(299, vec!["Chinfusor: Initialized successfully.".to_string()], "OK LOADED SUCCESSFULLY".to_string()));
+            }
 
-                println!("200 OK SPEAKING");
-                tx.send(SdInputCommand::Speak(text)).unwrap();
-                },
-            "CHAR" => {
-                let mut character_string=String::new();
-                let mut the_rest=String::new();
+        tx.send(ChinfusorEvent::SdInput(request)).unwrap();
+        }
+    }
 
-                println!("202 OK RECEIVING MESSAGE");
+#[cfg(test)]
+mod tests {
 
-                stdin.read_line(&mut character_string).unwrap();
-                stdin.read_line(&mut the_rest).unwrap();
+    use super::*;
+    use text_processor::identify_character;
 
-                println!("200 OK SPEAKING");
+    #[test]
+    fn speech_parameters_test() {
+        let mut config=Config::new();
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nchinese,u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,female1,some,-10,50,-20,80,no");
 
-                if let Some(ch)=character_string.chars().next() {
-                    tx.send(SdInputCommand::Char(ch)).unwrap();
-                    }
-                },
-            "KEY" => {
-                let mut key_string=String::new();
-                let mut the_rest=String::new();
+        //Without any SET, configured values are used as they are.
+        let mut parameters=SdSpeechParameters::new();
+        assert_eq!(Request::Set(vec!["language=cmn".to_string(), "voice=female1".to_string(), "punctuation_mode=some".to_string(), "pitch=-10".to_string(), "rate=-20".to_string(), "volume=80".to_string()]), SpeechSettings::generate_sd_command_from_engine_configuration(&config.engines[1], &parameters));
 
-                println!("202 OK RECEIVING MESSAGE");
+        //Rate and pitch are offsets, volume is relative to 100, results are clamped. Language and voice stay configured per alphabet, other settings are forwarded.
+        parameters.update(&SpeechSettings::new(vec!["rate=150".to_string(), "pitch=-5".to_string(), "volume=90".to_string(), "language=de".to_string(), "voice=male2".to_string(), "punctuation_mode=all".to_string(), "spelling=on".to_string(), "synthesis_voice=NULL".to_string()]), &config);
+        assert_eq!(Request::Set(vec!["language=cmn".to_string(), "voice=female1".to_string(), "punctuation_mode=all".to_string(), "pitch=-15".to_string(), "rate=80".to_string(), "volume=70".to_string(), "spelling=on".to_string()]), SpeechSettings::generate_sd_command_from_engine_configuration(&config.engines[1], &parameters));
+        assert_eq!(100, parameters.rate_for(&config.engines[0]));
+        assert_eq!(45, parameters.capitals_pitch_for(&config.engines[0]));
 
-                stdin.read_line(&mut key_string).unwrap();
-                stdin.read_line(&mut the_rest).unwrap();
+        //Labelled voices go to their alphabets, unlabelled ones to the latin alphabet.
+        parameters.update(&SpeechSettings::new(vec!["synthesis_voice=zh (chinese)".to_string(), "synthesis_voice=Anna".to_string(), "rate=x".to_string()]), &config);
+        assert_eq!(Some(&"zh".to_string()), parameters.synthesis_voice_for(&config.engines[1]));
+        assert_eq!(Some(&"Anna".to_string()), parameters.synthesis_voice_for(&config.engines[0]));
+        assert_eq!(100, parameters.rate);
+        }
 
-                key_string=key_string[..key_string.len()-1].to_string();
+    #[test]
+    fn configuration_errors_test() {
+        let mut config=Config::new();
+        let report=config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\n#A comment\nchinese,u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,most,10,50,2,1000,no\ncyrillic,u0x400-u0x52F,/usr/lib/speech-dispatcher-modules/sd_espeak-ng\nklingon,Klingon,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,tlh,male1,some,10,50,2,100,no\nlatin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,en,male1,some,10,50,2,100,no");
+
+        //Invalid values are replaced by defaults, lines with fatal errors are skipped.
+        assert_eq!(2, config.engines.len());
+        assert_eq!("sk", config.engines[0].language);
+        assert_eq!("some", config.engines[1].punctuation_mode);
+        assert_eq!(100, config.engines[1].volume);
+
+        assert_eq!(vec![
+            ConfigError::new("alphabets_settings.csv", 3, 129, "punctuation mode", "expected none, some or all, found \"most\", using some"),
+            ConfigError::new("alphabets_settings.csv", 3, 142, "volume", "expected a number from -100 to 100, found \"1000\", using 100"),
+            ConfigError::new("alphabets_settings.csv", 4, 1, "", "expected 12 or 13 fields separated by commas, found 3"),
+            ConfigError::new("alphabets_settings.csv", 5, 9, "unicode ranges", "unknown script or block name \"Klingon\""),
+            ConfigError::new("alphabets_settings.csv", 6, 1, "alphabet", "the latin alphabet is already defined on line 1, this definition is ignored"),
+            ], report.errors);
+        assert_eq!("alphabets_settings.csv, line 5, column 9, unicode ranges: unknown script or block name \"Klingon\".", report.errors[3].to_string());
+        assert!(report.alphabets_dropped);
+
+        //Invalid values and a duplicate latin alphabet don't make the configuration incomplete.
+        let report=config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,most,10,50,2,100,no\nlatin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,en,male1,some,10,50,2,100,no");
+        assert_eq!(2, report.errors.len());
+        assert!(!report.alphabets_dropped);
+
+        let report=config.load_configuration_from_string("overlapping_ranges: most_specific\nspeak_configuration_errors: yes\nunknown_setting: 1\nno setting here");
+        assert!(config.speak_configuration_errors);
+        assert_eq!(vec![
+            ConfigError::new("settings.conf", 1, 21, "overlapping_ranges", "expected first_defined or more_specific, found \"most_specific\""),
+            ConfigError::new("settings.conf", 3, 1, "unknown_setting", "unknown setting"),
+            ConfigError::new("settings.conf", 4, 1, "", "expected a setting in form key: value"),
+            ], report.errors);
+        }
 
-                println!("200 OK SPEAKING");
+    #[test]
+    fn overlapping_ranges_test() {
+        let mut config=Config::new();
+        let overlaps=config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nchinese,u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no\nkanji,u0x4E00-u0x4E10+u0x3040-u0x309F,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,ja,male1,some,10,50,2,100,no\ncyrillic,u0x400-u0x52F,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,ru,male1,some,10,50,2,100,no").overlaps;
 
-                tx.send(SdInputCommand::Key(key_string)).unwrap();
-                },
-            "PAUSE" => {
-                tx.send(SdInputCommand::Pause).unwrap();
-                },
-            "STOP" => {
-                tx.send(SdInputCommand::Stop).unwrap();
-                },
-            "QUIT" => tx.send(SdInputCommand::Quit).unwrap(),
-            "LIST VOICES" => {
-                println!("200-Default	{}	none", default_language);
-                println!("200 OK VOICE LIST SENT");
-                },
-            _ => {},
+        assert_eq!(vec![RangeOverlap {first_alphabet: "chinese".to_string(), second_alphabet: "kanji".to_string(), start: 0x4E00, end: 0x4E10}], overlaps);
+        assert_eq!("Unicode ranges of alphabets chinese and kanji overlap in interval u0x4E00-u0x4E10.", overlaps[0].to_string());
+
+        let alphabets_scheme=config.generate_alphabets_scheme();
+        assert_eq!(vec![0x400, 0x52F, 3, 0x3040, 0x309F, 2, 0x4E00, 0x9FA5, 1], alphabets_scheme);
+        assert_eq!(1, identify_character('丅', &alphabets_scheme));
+
+        config.load_configuration_from_string("overlapping_ranges: more_specific");
+        let alphabets_scheme=config.generate_alphabets_scheme();
+        assert_eq!(vec![0x400, 0x52F, 3, 0x3040, 0x309F, 2, 0x4E00, 0x4E10, 2, 0x4E11, 0x9FA5, 1], alphabets_scheme);
+        assert_eq!(2, identify_character('丅', &alphabets_scheme));
+        assert_eq!(1, identify_character('你', &alphabets_scheme));
+        assert_eq!(2, identify_character('あ', &alphabets_scheme));
+        }
+
+    #[test]
+    fn toml_configuration_test() {
+        let (config, report)=Config::load_toml_from_string("punctuation_characters = \",.\"\noverlapping_ranges = \"more_specific\"\n\n[alphabets.chinese]\nunicode_ranges = [\"Han\", \"u0xFF0C-u0xFF0C\"]\nlanguage = \"cmn\"\nrate = 500\n\n[alphabets.latin]\nlanguage = \"sk\"\nfirejail = true\ncolour = \"blue\"\n");
+
+        //The latin alphabet is moved to the front, missing fields have default values.
+        assert_eq!(2, config.engines.len());
+        assert_eq!("latin", config.engines[0].name);
+        assert_eq!("sk", config.engines[0].language);
+        assert!(config.engines[0].firejailed);
+        assert_eq!("chinese", config.engines[1].name);
+        assert_eq!("/usr/lib/speech-dispatcher-modules/sd_espeak-ng", config.engines[1].module);
+        assert_eq!(2, config.engines[1].rate);
+        assert_eq!(1, identify_character('你', &config.generate_alphabets_scheme()));
+        assert_eq!(1, identify_character('，', &config.generate_alphabets_scheme()));
+        assert!(config.punctuation_characters.contains(&','));
+
+        assert_eq!(vec![
+            ConfigError::new("chinfusor.toml", 7, 8, "rate", "expected a number from -100 to 100, found \"500\", using 2"),
+            ConfigError::new("chinfusor.toml", 12, 1, "colour", "unknown setting"),
+            ], report.errors);
+
+        assert!(!report.alphabets_dropped);
+
+        //Arrays are joined by the separators of each list, other keys don't accept them.
+        let (config, report)=Config::load_toml_from_string("prefer_neighbours = [\"latin\", \"chinese\"]\nalphabet_groups = [[\"latin\", \"chinese\"], [\"greek\", \"russian\"]]\nsound_icon_engine = [\"latin\"]\n\n[alphabets.latin]\nlanguage = [\"sk\", \"cs\"]\n");
+        assert_eq!(vec!["latin".to_string(), "chinese".to_string()], config.neighbour_preferring_alphabets);
+        assert_eq!(vec![vec!["latin".to_string(), "chinese".to_string()], vec!["greek".to_string(), "russian".to_string()]], config.alphabet_groups);
+        assert_eq!(None, config.sound_icon_engine);
+        assert_eq!("en", config.engines[0].language);
+        assert_eq!(vec![
+            ConfigError::new("chinfusor.toml", 3, 21, "sound_icon_engine", "expected a single value, not an array"),
+            ConfigError::new("chinfusor.toml", 6, 12, "language", "expected a single value, not an array"),
+            ], report.errors);
+
+        let (_, report)=Config::load_toml_from_string("[alphabets.latin\n");
+        assert_eq!(1, report.errors.len());
+        assert_eq!(1, report.errors[0].line);
+        assert!(report.alphabets_dropped);
+
+        //Converted configuration is loaded the same way as the original one.
+        let alphabets="latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\n#Chinese\n\"chinese\",u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,all,-10,50,-20,80,yes,10";
+        let settings="punctuation_characters: ,.?，。？- :()\nspeak_configuration_errors: yes";
+        let mut original=Config::new();
+        original.load_alphabets_from_string(alphabets);
+        original.load_configuration_from_string(settings);
+        let (converted, report)=Config::load_toml_from_string(&Config::convert_to_toml(alphabets, settings));
+
+        assert!(report.is_empty());
+        assert_eq!(original.generate_description(), converted.generate_description());
+
+        //Only the first alphabet of a name is converted, as TOML doesn't allow duplicate tables.
+        let duplicated=format!("{}\n\"chinese\",u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,zh,male1,all,0,50,0,80,yes", alphabets);
+        let toml=Config::convert_to_toml(&duplicated, settings);
+        assert!(toml.contains("\n#\"chinese\",u0x4E00-u0x9FA5"));
+        let (converted, report)=Config::load_toml_from_string(&toml);
+        assert!(report.is_empty());
+        assert_eq!(original.generate_description(), converted.generate_description());
+        }
+
+    #[test]
+    fn configuration_directory_test() {
+        let root=std::env::temp_dir().join(format!("chinfusor_configuration_directory_test_{}", std::process::id()));
+        let system=root.join("etc");
+        let home=root.join("home");
+        let xdg=root.join("xdg");
+        std::fs::create_dir_all(&system).unwrap();
+        std::fs::create_dir_all(home.join(".config/chinfusor")).unwrap();
+
+        let select=|explicit: Option<&str>, variables: &[(&str, &str)]| {
+            let variables: Vec<(String, String)>=variables.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+            select_configuration_directory(explicit.map(|directory| directory.to_string()), move |name| variables.iter().find(|(variable, _)| variable==name).map(|(_, value)| value.clone()), &system)
             };
+        let home_variable=home.to_str().unwrap();
+        let xdg_variable=xdg.to_str().unwrap();
+
+        //An explicit directory beats the environment variable, which beats everything else.
+        assert_eq!(PathBuf::from("/explicit"), select(Some("/explicit"), &[("CHINFUSOR_CONFIG_DIR", "/variable"), ("XDG_CONFIG_HOME", xdg_variable), ("HOME", home_variable)]));
+        assert_eq!(PathBuf::from("/variable"), select(None, &[("CHINFUSOR_CONFIG_DIR", "/variable"), ("XDG_CONFIG_HOME", xdg_variable), ("HOME", home_variable)]));
+
+        //XDG_CONFIG_HOME is used only if it's absolute, otherwise ~/.config is.
+        assert_eq!(xdg.join("chinfusor"), select(None, &[("XDG_CONFIG_HOME", xdg_variable), ("HOME", home_variable)]));
+        assert_eq!(home.join(".config/chinfusor"), select(None, &[("XDG_CONFIG_HOME", "relative"), ("HOME", home_variable)]));
+        assert_eq!(home.join(".config/chinfusor"), select(None, &[("HOME", home_variable)]));
+
+        //The system-wide directory is used only if it contains a configuration and the user has none.
+        std::fs::write(system.join(SETTINGS_FILE_NAME), "").unwrap();
+        assert_eq!(system, select(None, &[("HOME", home_variable)]));
+        assert_eq!(system, select(None, &[]));
+        std::fs::write(home.join(".config/chinfusor").join(SETTINGS_FILE_NAME), "").unwrap();
+        assert_eq!(home.join(".config/chinfusor"), select(None, &[("HOME", home_variable)]));
+        assert_eq!(Path::new("/explicit"), select(Some("/explicit"), &[("HOME", home_variable)]));
+
+        std::fs::remove_dir_all(&root).unwrap();
         }
-    }
 
+    }
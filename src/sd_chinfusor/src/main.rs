@@ -1,10 +1,130 @@
-use sd_chinfusor::{Config, run};
+use std::io::Read;
+use std::path::Path;
+
+use sd_chinfusor::text_processor::parse_text;
+use sd_chinfusor::{Config, ConfigReport, find_configuration_directory, run, ALPHABETS_FILE_NAME, CONFIGURATION_REPORT_FILE_NAME, SETTINGS_FILE_NAME, TOML_FILE_NAME};
 
 fn main()
     {
-    let mut config=Config::new();
-    config.load_alphabets_from_file(&(std::env::var("HOME").unwrap()+"/.config/chinfusor/alphabets_settings.csv"));
-    config.load_configuration_from_file(&(std::env::var("HOME").unwrap()+"/.config/chinfusor/settings.conf"));
-    run(config);
+    //Speech-dispatcher passes path to the module's configuration file as the first argument, so unknown arguments must be ignored.
+    let args: Vec<String>=std::env::args().skip(1).collect();
+
+    //The directory can be given as --config-dir directory or --config-dir=directory.
+    let explicit_configuration_directory=args.iter().enumerate().find_map(|(i, arg)| {
+        if arg=="--config-dir" {
+            args.get(i+1).cloned()
+            }
+        else {
+            arg.strip_prefix("--config-dir=").map(|directory| directory.to_string())
+            }
+        });
+    let configuration_directory=find_configuration_directory(explicit_configuration_directory);
+
+    if args.iter().any(|arg| arg=="--convert-config") {
+        convert_config(&configuration_directory);
+        }
+
+    let (config, report)=Config::load_from_directory(&configuration_directory);
+
+    if args.iter().any(|arg| arg=="--check-config") {
+        check_config(&config, &report);
+        }
+    if let Some(position)=args.iter().position(|arg| arg=="--split") {
+        let text=args.get(position+1).filter(|arg| !arg.starts_with("--")).cloned();
+        split(&config, &report, text, args.iter().any(|arg| arg=="--json"), args.iter().any(|arg| arg=="--ssml"));
+        }
+
+    report.log();
+    report.write_to_file(&configuration_directory.join(CONFIGURATION_REPORT_FILE_NAME).to_string_lossy());
+
+    run(config, report, &configuration_directory);
+    }
+
+//Prints the configuration as Chinfusor understands it, together with all problems found in it, and exits. The exit status is non-zero, if there are errors. Overlaps alone are not errors, as they're resolved by the overlapping_ranges setting.
+fn check_config(config: &Config, report: &ConfigReport) {
+    print!("{}", config.generate_description());
+
+    if report.is_empty() {
+        println!("No problems found.");
+        }
+    else {
+        println!("Problems:");
+        print!("{}", report);
+        }
+
+    std::process::exit(if report.errors.len()>0 { 1 } else { 0 });
     }
+//Writes chinfusor.toml with the content of alphabets_settings.csv and settings.conf, and exits. An existing chinfusor.toml is never overwritten.
+fn convert_config(configuration_directory: &Path) {
+    let toml_file_path=configuration_directory.join(TOML_FILE_NAME);
+    if toml_file_path.exists() {
+        eprintln!("{} already exists, remove it first, if you want to convert the older configuration files again.", toml_file_path.display());
+        std::process::exit(1);
+        }
+
+    let alphabets=std::fs::read_to_string(configuration_directory.join(ALPHABETS_FILE_NAME)).unwrap_or(String::new());
+    let settings=std::fs::read_to_string(configuration_directory.join(SETTINGS_FILE_NAME)).unwrap_or(String::new());
 
+    if let Err(e)=std::fs::write(&toml_file_path, Config::convert_to_toml(&alphabets, &settings)) {
+        eprintln!("Unable to write {}, {}.", toml_file_path.display(), e);
+        std::process::exit(1);
+        }
+
+    println!("The configuration was written to {}, alphabets_settings.csv and settings.conf are no longer used.", toml_file_path.display());
+    std::process::exit(0);
+    }
+//Prints chunks, into which the text would be split when spoken, and exits. The text is read from standard input, if it isn't given as an argument. No speech modules are started.
+fn split(config: &Config, report: &ConfigReport, text: Option<String>, json: bool, ssml: bool) {
+    report.log();
+
+    let text=match text {
+        Some(text) => text,
+        None => {
+            let mut text=String::new();
+            if std::io::stdin().read_to_string(&mut text).is_err() {
+                eprintln!("Unable to read the text from standard input.");
+                std::process::exit(1);
+                }
+            text
+            },
+        };
+
+    let chunks=parse_text(&text, &config.generate_alphabets_scheme(), &config.punctuation_characters, &config.generate_chunking_options(), ssml);
+
+    if json {
+        let chunks: Vec<String>=chunks.iter()
+        .map(|chunk| {
+            let engine=&config.engines[chunk.0];
+            format!("{{\"alphabet\": {}, \"module\": {}, \"language\": {}, \"text\": {}}}", json_string(&engine.name), json_string(&engine.module), json_string(&engine.language), json_string(&chunk.1))
+            })
+        .collect();
+
+        println!("[{}]", chunks.join(",\n"));
+        }
+    else {
+        for chunk in &chunks {
+            let engine=&config.engines[chunk.0];
+            println!("{} ({}, {}): {}", engine.name, engine.module, engine.language, chunk.1);
+            }
+        }
+
+    std::process::exit(0);
+    }
+fn json_string(s: &str) -> String {
+    let mut result=String::from("\"");
+
+    for ch in s.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            ch if (ch as u32)<0x20 => result.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => result.push(ch),
+            };
+        }
+
+    result.push('"');
+    result
+    }
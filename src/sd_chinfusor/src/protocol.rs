@@ -0,0 +1,383 @@
+//The protocol spoken between speech-dispatcher and its output modules. Chinfusor uses it in both directions, it's a module for speech-dispatcher and a speech-dispatcher for the modules it runs.
+
+use std::fmt;
+
+//Commands followed by a block of data.
+const BLOCK_COMMANDS: [&str; 7]=["AUDIO", "LOGLEVEL", "SET", "SPEAK", "CHAR", "KEY", "SOUND_ICON"];
+
+//A command sent to a speech module. Commands followed by a block of data carry its lines, the block itself is terminated by a line containing a single dot.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Init,
+    Audio(Vec<String>),
+    LogLevel(Vec<String>),
+    Set(Vec<String>),
+    Speak(String),
+    Char(String),
+    Key(String),
+    SoundIcon(String),
+    ListVoices,
+    Pause,
+    Stop,
+    Quit,
+    }
+impl Request {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Init => "INIT",
+            Request::Audio(_) => "AUDIO",
+            Request::LogLevel(_) => "LOGLEVEL",
+            Request::Set(_) => "SET",
+            Request::Speak(_) => "SPEAK",
+            Request::Char(_) => "CHAR",
+            Request::Key(_) => "KEY",
+            Request::SoundIcon(_) => "SOUND_ICON",
+            Request::ListVoices => "LIST VOICES",
+            Request::Pause => "PAUSE",
+            Request::Stop => "STOP",
+            Request::Quit => "QUIT",
+            }
+        }
+    pub fn is_speech(&self) -> bool {
+        Request::is_speech_command(self.name())
+        }
+    pub fn is_speech_command(name: &str) -> bool {
+        name=="SPEAK" || name=="CHAR" || name=="KEY" || name=="SOUND_ICON"
+        }
+    //Codes of replies, which a module answers the request with, in the order they come.
+    pub fn expected_reply_codes(&self) -> &'static [u32] {
+        match self {
+            Request::Init => &[299],
+            Request::Audio(_) | Request::LogLevel(_) => &[207, 203],
+            Request::Set(_) => &[203, 203],
+            Request::Speak(_) | Request::Char(_) | Request::Key(_) | Request::SoundIcon(_) => &[202, 200],
+            Request::ListVoices => &[200],
+            Request::Pause | Request::Stop | Request::Quit => &[],
+            }
+        }
+
+    pub fn encode(&self) -> String {
+        match self {
+            Request::Audio(lines) | Request::LogLevel(lines) | Request::Set(lines) => Request::encode_block(self.name(), &lines.join("\n")),
+            Request::Speak(data) | Request::Char(data) | Request::Key(data) | Request::SoundIcon(data) => Request::encode_block(self.name(), data),
+            _ => format!("{}\n", self.name()),
+            }
+        }
+
+    fn encode_block(name: &str, data: &str) -> String {
+        let lines: Vec<String>=data.split('\n').map(|line| escape_line(line)).collect();
+
+        format!("{}\n{}\n.\n", name, lines.join("\n"))
+        }
+    }
+//A reply or an event received from a speech module. Replies with codes from 200 to 299 confirm commands, codes from 300 up signal errors, and codes from 700 to 704 are events produced while speaking.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Ok(u32, Vec<String>, String),
+    Error(u32, String),
+    IndexMark(String),
+    Begin,
+    End,
+    Stop,
+    Pause,
+    Unknown(String),
+    }
+impl Response {
+
+    //The reply confirming, that a module is ready to receive data of the given command.
+    pub fn data_requested(name: &str) -> Option<Response> {
+        match name {
+            "AUDIO" => Some(Response::Ok(207, Vec::new(), "OK RECEIVING AUDIO SETTINGS".to_string())),
+            "LOGLEVEL" => Some(Response::Ok(207, Vec::new(), "OK RECEIVING LOGLEVEL SETTINGS".to_string())),
+            "SET" => Some(Response::Ok(203, Vec::new(), "OK RECEIVING SETTINGS".to_string())),
+            name if Request::is_speech_command(name) => Some(Response::Ok(202, Vec::new(), "OK RECEIVING MESSAGE".to_string())),
+            _ => None,
+            }
+        }
+    //The reply confirming, that data of the given request were received.
+    pub fn data_received(request: &Request) -> Option<Response> {
+        match request {
+            Request::Audio(_) => Some(Response::Ok(203, Vec::new(), "OK AUDIO INITIALIZED".to_string())),
+            Request::LogLevel(_) => Some(Response::Ok(203, Vec::new(), "OK LOGLEVEL SET".to_string())),
+            Request::Set(_) => Some(Response::Ok(203, Vec::new(), "OK SETTINGS RECEIVED".to_string())),
+            request if request.is_speech() => Some(Response::Ok(200, Vec::new(), "OK SPEAKING".to_string())),
+            _ => None,
+            }
+        }
+
+    pub fn encode(&self) -> String {
+        match self {
+            Response::Ok(code, data, text) => {
+                let mut result=String::new();
+                for line in data {
+                    result+=&format!("{}-{}\n", code, line);
+                    }
+
+                result+&format!("{} {}\n", code, text)
+                },
+            Response::Error(code, text) => format!("{} {}\n", code, text),
+            Response::IndexMark(name) => format!("700-{}\n700 INDEX MARK\n", name),
+            Response::Begin => "701 BEGIN\n".to_string(),
+            Response::End => "702 END\n".to_string(),
+            Response::Stop => "703 STOP\n".to_string(),
+            Response::Pause => "704 PAUSE\n".to_string(),
+            Response::Unknown(line) => format!("{}\n", line),
+            }
+        }
+    }
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    UnknownCommand(String),
+    InvalidData(&'static str, String),
+    UnexpectedEof(&'static str),
+    }
+impl ProtocolError {
+
+    //The reply to be sent back instead of the regular one.
+    pub fn response(&self) -> Response {
+        match self {
+            ProtocolError::UnknownCommand(_) => Response::Error(300, "ERR UNKNOWN COMMAND".to_string()),
+            _ => Response::Error(301, "ERROR INVALID DATA".to_string()),
+            }
+        }
+    }
+impl fmt::Display for ProtocolError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownCommand(command) => write!(f, "Unknown command {}.", command),
+            ProtocolError::InvalidData(command, description) => write!(f, "Invalid data of {}: {}.", command, description),
+            ProtocolError::UnexpectedEof(command) => write!(f, "Input ended in the middle of {}.", command),
+            }
+        }
+    }
+pub enum ParsedRequest {
+    //The command expects a block of data, the sender should be told, that it can send it.
+    DataRequested(&'static str),
+    Complete(Request),
+    }
+//Reads requests line by line, so it can be fed directly from a pipe. Data blocks are collected until their terminating dot arrives.
+pub struct RequestParser {
+    block: Option<(&'static str, Vec<String>)>,
+    }
+impl RequestParser {
+
+    pub fn new() -> RequestParser {
+        RequestParser {block: None}
+        }
+
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<ParsedRequest>, ProtocolError> {
+        let line=line.strip_suffix('\n').unwrap_or(line);
+        let line=line.strip_suffix('\r').unwrap_or(line);
+
+        if let Some((name, mut data))=self.block.take() {
+            if line!="." {
+                data.push(unescape_line(line).to_string());
+                self.block=Some((name, data));
+                return Ok(None);
+                }
+
+            return RequestParser::finish_block(name, data).map(|request| Some(ParsedRequest::Complete(request)));
+            }
+
+        if let Some(name)=BLOCK_COMMANDS.iter().find(|name| **name==line.trim()) {
+            self.block=Some((name, Vec::new()));
+            return Ok(Some(ParsedRequest::DataRequested(name)));
+            }
+
+        let request=match line.trim() {
+            "INIT" => Request::Init,
+            "LIST VOICES" => Request::ListVoices,
+            "PAUSE" => Request::Pause,
+            "STOP" => Request::Stop,
+            "QUIT" => Request::Quit,
+            "" => return Ok(None),
+            command => return Err(ProtocolError::UnknownCommand(command.to_string())),
+            };
+
+        Ok(Some(ParsedRequest::Complete(request)))
+        }
+    //Should be called, when the input has ended. Reports a data block, which was left unterminated.
+    pub fn finish(&mut self) -> Result<(), ProtocolError> {
+        match self.block.take() {
+            Some((name, _)) => Err(ProtocolError::UnexpectedEof(name)),
+            None => Ok(()),
+            }
+        }
+
+    fn finish_block(name: &'static str, data: Vec<String>) -> Result<Request, ProtocolError> {
+        match name {
+            "AUDIO" | "LOGLEVEL" | "SET" => {
+                let mut lines=Vec::new();
+                for line in data {
+                    let line=line.trim().to_string();
+
+                    if line.len()==0 {
+                        continue;
+                        }
+                    if !line.contains('=') {
+                        return Err(ProtocolError::InvalidData(name, format!("expected a key=value pair, found \"{}\"", line)));
+                        }
+
+                    lines.push(line);
+                    }
+
+                Ok(match name {
+                    "AUDIO" => Request::Audio(lines),
+                    "LOGLEVEL" => Request::LogLevel(lines),
+                    _ => Request::Set(lines),
+                    })
+                },
+            "SPEAK" => Ok(Request::Speak(data.join("\n"))),
+            _ => {
+                //Characters, keys and sound icons are sent on a single line.
+                if data.len()!=1 || data[0].len()==0 {
+                    return Err(ProtocolError::InvalidData(name, format!("expected a single line, found {} lines", data.len())));
+                    }
+
+                let value=data.into_iter().next().unwrap();
+
+                Ok(match name {
+                    "CHAR" => Request::Char(value),
+                    "KEY" => Request::Key(value),
+                    _ => Request::SoundIcon(value),
+                    })
+                },
+            }
+        }
+    }
+//A line of data starting with a dot is sent with the dot doubled, so it can't be mistaken for the end of the block.
+pub fn escape_line(line: &str) -> String {
+    if line.starts_with('.') {
+        format!(".{}", line)
+        }
+    else {
+        line.to_string()
+        }
+    }
+pub fn unescape_line(line: &str) -> &str {
+    if line.starts_with("..") {
+        &line[1..]
+        }
+    else {
+        line
+        }
+    }
+//Replies consisting of more lines have all but the last line in form NNN-data, so these need to be collected until the final NNN text line arrives.
+pub struct ResponseParser {
+    data: Vec<String>,
+    }
+impl ResponseParser {
+
+    pub fn new() -> ResponseParser {
+        ResponseParser {data: Vec::new()}
+        }
+
+    pub fn parse_line(&mut self, line: &str) -> Option<Response> {
+        let code=match line.get(..3).and_then(|code| code.parse::<u32>().ok()) {
+            Some(code) => code,
+            None => return Some(Response::Unknown(line.to_string())),
+            };
+        let text=line.get(4..).unwrap_or("").to_string();
+
+        match line.get(3..4) {
+            Some("-") => {
+                self.data.push(text);
+                return None;
+                },
+            Some(" ") | None => {},
+            _ => return Some(Response::Unknown(line.to_string())),
+            };
+
+        let data=std::mem::take(&mut self.data);
+
+        Some(match code {
+            700 => Response::IndexMark(data.into_iter().next().unwrap_or(text)),
+            701 => Response::Begin,
+            702 => Response::End,
+            703 => Response::Stop,
+            704 => Response::Pause,
+            200..=299 => Response::Ok(code, data, text),
+            _ => Response::Error(code, text),
+            })
+        }
+    }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn parse_requests(input: &str) -> (Vec<String>, Vec<Result<Request, ProtocolError>>) {
+        let mut parser=RequestParser::new();
+        let (mut data_requests, mut requests)=(Vec::new(), Vec::new());
+
+        for line in input.split_inclusive('\n') {
+            match parser.parse_line(line) {
+                Ok(Some(ParsedRequest::DataRequested(name))) => data_requests.push(name.to_string()),
+                Ok(Some(ParsedRequest::Complete(request))) => requests.push(Ok(request)),
+                Ok(None) => {},
+                Err(e) => requests.push(Err(e)),
+                };
+            }
+        if let Err(e)=parser.finish() {
+            requests.push(Err(e));
+            }
+
+        (data_requests, requests)
+        }
+
+    #[test]
+    fn request_parsing_test() {
+        let input="INIT\nAUDIO\naudio_output_method=pulse\n.\nSET\nrate=20\n pitch=-10 \n.\nSPEAK\nHello,\nworld.\n.\nCHAR\nA\n.\nKEY\nctrl_a\n.\nLIST VOICES\nSTOP\nQUIT\n";
+        let (data_requests, requests)=parse_requests(input);
+
+        assert_eq!(vec!["AUDIO", "SET", "SPEAK", "CHAR", "KEY"], data_requests);
+        assert_eq!(vec![Ok(Request::Init), Ok(Request::Audio(vec!["audio_output_method=pulse".to_string()])), Ok(Request::Set(vec!["rate=20".to_string(), "pitch=-10".to_string()])), Ok(Request::Speak("Hello,\nworld.".to_string())), Ok(Request::Char("A".to_string())), Ok(Request::Key("ctrl_a".to_string())), Ok(Request::ListVoices), Ok(Request::Stop), Ok(Request::Quit)], requests);
+        }
+
+    #[test]
+    fn malformed_request_test() {
+        let (_, requests)=parse_requests("HELLO\nCHAR\na\nb\n.\nSET\nrate\n.\nINIT\nSPEAK\nUnfinished");
+
+        assert_eq!(vec![Err(ProtocolError::UnknownCommand("HELLO".to_string())), Err(ProtocolError::InvalidData("CHAR", "expected a single line, found 2 lines".to_string())), Err(ProtocolError::InvalidData("SET", "expected a key=value pair, found \"rate\"".to_string())), Ok(Request::Init), Err(ProtocolError::UnexpectedEof("SPEAK"))], requests);
+        }
+
+    #[test]
+    fn request_encoding_test() {
+        let requests=vec![Request::Init, Request::Set(vec!["rate=20".to_string(), "volume=100".to_string()]), Request::Speak("<speak>Hello\nworld</speak>".to_string()), Request::Char("a".to_string()), Request::Pause];
+        let encoded: String=requests.iter().map(|request| request.encode()).collect();
+
+        assert_eq!("INIT\nSET\nrate=20\nvolume=100\n.\nSPEAK\n<speak>Hello\nworld</speak>\n.\nCHAR\na\n.\nPAUSE\n", encoded);
+        assert_eq!(requests, parse_requests(&encoded).1.into_iter().map(|request| request.unwrap()).collect::<Vec<Request>>());
+        }
+
+    #[test]
+    fn dot_escaping_test() {
+        let (_, requests)=parse_requests("SPEAK\nfn main() {\n..\n....and then\n.\nSET\n..hidden=1\n.\n");
+        assert_eq!(vec![Ok(Request::Speak("fn main() {\n.\n...and then".to_string())), Ok(Request::Set(vec![".hidden=1".to_string()]))], requests);
+
+        let request=Request::Speak("<speak>Wait\n.\n...what?\n. .</speak>".to_string());
+        assert_eq!("SPEAK\n<speak>Wait\n..\n....what?\n.. .</speak>\n.\n", request.encode());
+        assert_eq!(vec![Ok(request.clone())], parse_requests(&request.encode()).1);
+
+        assert_eq!("..", escape_line("."));
+        assert_eq!("text.", escape_line("text."));
+        assert_eq!(".", unescape_line(".."));
+        assert_eq!(".x", unescape_line(".x"));
+        }
+
+    #[test]
+    fn response_test() {
+        let responses=vec![Response::Ok(299, vec!["Chinfusor: Initialized successfully.".to_string()], "OK LOADED SUCCESSFULLY".to_string()), Response::Begin, Response::IndexMark("5".to_string()), Response::End, Response::Error(301, "ERROR CANT SPEAK".to_string()), Response::Unknown("garbage".to_string())];
+        let encoded: String=responses.iter().map(|response| response.encode()).collect();
+
+        assert_eq!("299-Chinfusor: Initialized successfully.\n299 OK LOADED SUCCESSFULLY\n701 BEGIN\n700-5\n700 INDEX MARK\n702 END\n301 ERROR CANT SPEAK\ngarbage\n", encoded);
+
+        let mut parser=ResponseParser::new();
+        let parsed: Vec<Response>=encoded.lines().filter_map(|line| parser.parse_line(line)).collect();
+        assert_eq!(responses, parsed);
+        }
+
+    }
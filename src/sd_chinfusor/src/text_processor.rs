@@ -1,60 +1,189 @@
 use std::collections::HashSet;
 
+use regex::Regex;
+
+use super::unicode_data::is_neutral;
+
+lazy_static! {
+    static ref LEADING_MARK_MATCHING_REGEX: Regex=Regex::new(
+    r#"^\s*<mark\s+name\s*=\s*("([^"]*)"|'([^']*)')\s*/>"# //Matches strings of style <mark name="abc"/> at the beginning of a text
+    ).unwrap();
+    }
+
 #[derive(Debug, PartialEq)]
 pub struct LanguageChunk(pub usize, pub String);
 
-//Alphabets cheme is a vec consisting of triplets of u32s, where first value is unicode range start, the second unicode range end, and the thirdone id of the alphabet for which the range is defined. These triplets are sorted in ascending order with the range start as sorting key, so it is easy to search through them.
+//Decides, which chunk gets neutral characters, like digits or symbols, which don't belong to ranges of any alphabet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NeutralCharacterPolicy {
+    //Attached to the previous chunk, or the next one at the beginning of the text
+    Previous,
+    //Attached to the next chunk, or the previous one at the end of the text
+    Next,
+    //Neutral characters are not recognized, they're read by the latin alphabet as any other unassigned characters.
+    Disabled,
+    }
 
-pub fn parse_text(text: &str, alphabets_scheme: &Vec<u32>, punctuation_characters: &HashSet<char>, ssml: bool) -> Vec<LanguageChunk> {
-    if alphabets_scheme.len()==0 {
-        return vec![LanguageChunk(0, text.to_string())];
-        }
+//Rules for merging chunks into their neighbours, indexed by ids of alphabets. Alphabets without an entry have no minimum length.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChunkMerging {
+    //Chunks with fewer letters are read by the surrounding alphabet, if the chunks before and after them belong to the same one.
+    pub minimum_lengths: Vec<usize>,
+    //Short chunks of these alphabets are read by the neighbouring alphabet also when the neighbours differ.
+    pub preferring_neighbours: Vec<bool>,
+    //Groups of alphabets, where chunks of the other alphabets next to a chunk of the first one are read by the first one, like kanji next to kana.
+    pub alphabet_groups: Vec<(usize, Vec<usize>)>,
+    }
 
-    let chars: Vec<char>=text.chars().collect();
-    if chars.len()==0 {
-        return Vec::new();
+//Identifies languages of texts written in the Latin script, so they can be read by alphabets of those languages.
+pub trait LanguageDetector {
+    //Returns index of the most probable of the given languages together with confidence from 0 to 1, or None if the text gives no clue. Languages are ISO 639-1 codes, possibly followed by a region.
+    fn detect(&self, text: &str, languages: &[String]) -> Option<(usize, f64)>;
+    }
+
+//Alphabets of a group don't take over chunks across these characters.
+const SENTENCE_TERMINATORS: [char; 6]=['。', '！', '？', '.', '!', '?'];
+//Languages known to WordListDetector with their most frequent words and letters, which are characteristic for them.
+const WORD_LISTS: [(&str, &str, &str); 6]=[
+    ("en", "a about an and are at be but by can do does for from has have he i if in is it my not of on or she that the their there they this to was we were what which will with would yes you your", ""),
+    ("sk", "a ako ale alebo aj áno by bol bola bolo čo ďakujem deň do dobrý je ja kde ktorá ktoré ktorý len ma mi my na nie od on ona po prečo pre pri sa si som sú tak to už v vo z zo že", "áäčďéíĺľňóôŕšťúýž"),
+    ("cs", "a ale ano by byl byla bylo co děkuji do je jako jen jsem jsou ještě kde která které který mi na ne nebo od po pro proč při se tak to už v ve z ze že", "áčďéěíňóřšťúůýž"),
+    ("de", "aber an auch auf bei das dass dem den der des die du ein eine einen er es für habe haben hat ich ihr im in ist mit nach nicht noch nur oder sich sie sind und von war was werden wie wir wird zu", "äöüß"),
+    ("fr", "au aux avec ce cette dans de des du elle est et il ils je la le les mais ne nous ou pas pour que qui sont sur tu un une vous", "àâæçéèêëîïôœùûüÿ"),
+    ("es", "al como con de del el ella en es está la las lo los muy no para pero por que se son su un una y yo él", "áéíñóúü"),
+    ];
+
+//Scores languages by frequent words and characteristic letters found in the text. It works offline and needs no training data, which makes it suitable for sentences, but not for single words, which are common to more languages.
+#[derive(Default)]
+pub struct WordListDetector {
+    }
+impl WordListDetector {
+
+    pub fn new() -> WordListDetector {
+        WordListDetector {}
         }
-    let mut result: Vec<LanguageChunk>=Vec::new();
+    }
+impl LanguageDetector for WordListDetector {
+
+    fn detect(&self, text: &str, languages: &[String]) -> Option<(usize, f64)> {
+        let words: Vec<String>=text.split(|ch: char| !ch.is_alphabetic())
+        .filter(|word| word.len()>0)
+        .map(|word| word.to_lowercase())
+        .collect();
+        if words.len()==0 {
+            return None;
+            }
 
-    let lower_threshold=alphabets_scheme[0];
-    let upper_threshold=alphabets_scheme[alphabets_scheme.len()-2];
-    let get_alphabet_id=|ch| {
-        if ch<lower_threshold || ch>upper_threshold {
-            return 0;
+        //Words of each language, which are either frequent in it or contain its characteristic letters
+        let mut scores: Vec<usize>=Vec::new();
+        for language in languages {
+            let code=language.to_lowercase().split(['-', '_']).next().unwrap_or("").to_string();
+            let score=match WORD_LISTS.iter().find(|(list_code, _, _)| *list_code==code) {
+                Some((_, list, letters)) => words.iter()
+                .filter(|word| list.split(' ').any(|frequent_word| frequent_word==word.as_str()) || word.chars().any(|ch| letters.contains(ch)))
+                .count(),
+                None => 0,
+                };
+            scores.push(score);
             }
 
-        if ch<=alphabets_scheme[1] {
-            return alphabets_scheme[2] as usize;
+        let (best, best_score)=scores.iter().cloned().enumerate().max_by_key(|(i, score)| (*score, usize::MAX-i))?;
+        if best_score==0 {
+            return None;
             }
+        let second_score=scores.iter().cloned().enumerate().filter(|(i, _)| *i!=best).map(|(_, score)| score).max().unwrap_or(0);
 
-        for i in (3..alphabets_scheme.len()).step_by(3) {
-            let (lower_boundary, upper_boundary)=(alphabets_scheme[i], alphabets_scheme[i+1]);
+        Some((best, (best_score-second_score) as f64/words.len() as f64))
+        }
+    }
 
-            if ch<lower_boundary {
-                return 0;
-                }
-            if ch<=upper_boundary {
-                return alphabets_scheme[i+2] as usize;
-                }
-            }
+//Alphabets selected by detection of languages instead of Unicode ranges. Generated by Config::generate_language_detection.
+pub struct LanguageDetection {
+    pub detector: Box<dyn LanguageDetector>,
+    //Ids of alphabets with their languages. The first one is the latin alphabet, which gets the text, if the detector isn't confident enough.
+    pub alphabets: Vec<(usize, String)>,
+    pub threshold: f64,
+    }
+impl LanguageDetection {
 
-        return 0;
-        };
+    pub fn is_active(&self) -> bool {
+        self.alphabets.len()>1
+        }
+    }
+impl Default for LanguageDetection {
+
+    fn default() -> LanguageDetection {
+        LanguageDetection {detector: Box::new(WordListDetector::new()), alphabets: Vec::new(), threshold: 0.3}
+        }
+    }
+
+//Everything, what decides about chunks beside the alphabets scheme and punctuation. Generated by Config::generate_chunking_options.
+pub struct ChunkingOptions {
+    pub neutral_character_policy: NeutralCharacterPolicy,
+    pub chunk_merging: ChunkMerging,
+    pub language_detection: LanguageDetection,
+    }
+impl Default for ChunkingOptions {
+
+    //Text is split only by ranges of alphabets.
+    fn default() -> ChunkingOptions {
+        ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Disabled, chunk_merging: ChunkMerging::default(), language_detection: LanguageDetection::default()}
+        }
+    }
+
+enum SsmlTag {
+    Opening(String),
+    Closing(String),
+    SelfClosing(String),
+    //Comments, declarations and processing instructions
+    Other,
+    }
+
+//Alphabets cheme is a vec consisting of triplets of u32s, where first value is unicode range start, the second unicode range end, and the thirdone id of the alphabet for which the range is defined. These triplets are sorted in ascending order with the range start as sorting key, so it is easy to search through them.
+
+pub fn parse_text(text: &str, alphabets_scheme: &[u32], punctuation_characters: &HashSet<char>, options: &ChunkingOptions, ssml: bool) -> Vec<LanguageChunk> {
+    if alphabets_scheme.len()==0 && !options.language_detection.is_active() {
+        let result=vec![LanguageChunk(0, text.to_string())];
+        return if ssml { balance_ssml_tags(result) } else { result };
+        }
+
+    let chars: Vec<char>=text.chars().collect();
+    if chars.len()==0 {
+        return Vec::new();
+        }
+    //Chunks are collected as alphabet ids with start and end positions first, so short ones can be merged without altering the text between them.
+    let mut chunks: Vec<(usize, usize, usize)>=Vec::new();
+
+    let get_alphabet_id=|ch: char| find_alphabet_id(ch as u32, alphabets_scheme).unwrap_or(0);
+    //Characters outside of ranges of all alphabets may be neutral, explicitly assigned characters never are.
+    let is_neutral_character=|ch: char| options.neutral_character_policy!=NeutralCharacterPolicy::Disabled && find_alphabet_id(ch as u32, alphabets_scheme).is_none() && is_neutral(ch);
 
     let mut last_mark=0;
     let mut chunk_type_set=false;
-    let mut current_chunk_type=if ssml && chars[0]=='<' {
+    let mut current_chunk_type=if (ssml && chars[0]=='<') || is_neutral_character(chars[0]) {
         0
         } else {
         chunk_type_set=true;
-        get_alphabet_id(chars[0] as u32)
+        get_alphabet_id(chars[0])
         };
     let mut in_tag=false;
 
+    //Index marks and opening tags located between the last character of a chunk and the first character of the next one belong to the latter, so they need to be moved there. This variable holds the position of the first such tag. Closing tags belong to the previous chunk, so everything before them stays there.
+    let mut pending_tags_start: Option<usize>=None;
+    //With NeutralCharacterPolicy::Next, neutral characters between two chunks are moved to the latter as well. This variable holds the position of the first of them.
+    let mut pending_neutral_start: Option<usize>=None;
+
     for (i, ch) in chars.iter().enumerate() {
         if ssml {
             if *ch=='<' && !in_tag {
                 in_tag=true;
+
+                if chars.get(i+1)==Some(&'/') {
+                    pending_tags_start=None;
+                    }
+                else if pending_tags_start.is_none() {
+                    pending_tags_start=Some(i);
+                    }
                 }
             if *ch=='>' && in_tag {
                 in_tag=false;
@@ -70,64 +199,413 @@ pub fn parse_text(text: &str, alphabets_scheme: &Vec<u32>, punctuation_character
             continue;
             }
 
+        if is_neutral_character(*ch) {
+            if options.neutral_character_policy==NeutralCharacterPolicy::Next && chunk_type_set && pending_neutral_start.is_none() {
+                pending_neutral_start=Some(pending_tags_start.unwrap_or(i));
+                }
+            //With NeutralCharacterPolicy::Previous, neutral characters stay in the current chunk, so tags before them are not at its end anymore.
+            if options.neutral_character_policy==NeutralCharacterPolicy::Previous && chunk_type_set {
+                pending_tags_start=None;
+                }
+            continue;
+            }
+
         if !chunk_type_set {
-            current_chunk_type=get_alphabet_id(*ch as u32);
+            current_chunk_type=get_alphabet_id(*ch);
             chunk_type_set=true;
+            pending_tags_start=None;
             continue;
             }
 
-        let new_chunk_type=get_alphabet_id(*ch as u32);
+        let new_chunk_type=get_alphabet_id(*ch);
 
         if new_chunk_type!=current_chunk_type {
-            let collected_text=(&chars[last_mark..i]).iter().collect::<String>().trim().to_string();
-            result.push(LanguageChunk(current_chunk_type, collected_text));
+            let chunk_end=match (pending_tags_start, pending_neutral_start) {
+                (Some(tags_start), Some(neutral_start)) => tags_start.min(neutral_start),
+                (tags_start, neutral_start) => tags_start.or(neutral_start).unwrap_or(i),
+                };
+
+            chunks.push((current_chunk_type, last_mark, chunk_end));
 
             current_chunk_type=new_chunk_type;
-            last_mark=i;
+            last_mark=chunk_end;
             }
 
+        pending_tags_start=None;
+        pending_neutral_start=None;
+
         }
 
     //Process the last chunk
 
-    let collected_text=(&chars[last_mark..]).iter().collect::<String>().trim().to_string();
-    result.push(LanguageChunk(current_chunk_type, collected_text));
+    chunks.push((current_chunk_type, last_mark, chars.len()));
+
+    apply_alphabet_groups(&mut chunks, &chars, &options.chunk_merging, ssml);
+    detect_languages(&mut chunks, &chars, &options.language_detection, ssml);
+    merge_short_chunks(&mut chunks, &chars, punctuation_characters, &options.chunk_merging, ssml);
+
+    let result: Vec<LanguageChunk>=chunks.iter()
+    .map(|(alphabet_id, start, end)| LanguageChunk(*alphabet_id, chars[*start..*end].iter().collect::<String>().trim().to_string()))
+    .collect();
+
+    if ssml {
+        return balance_ssml_tags(result);
+        }
 
     result
     }
-pub fn identify_character(character: char, alphabets_scheme: &Vec<u32>) -> usize {
-    if alphabets_scheme.len()==0 {
-        return 0;
+//Splits chunks of the latin alphabet into sentences and gives each of them to the alphabet of its detected language. Neighbouring sentences of the same alphabet are joined again.
+fn detect_languages(chunks: &mut Vec<(usize, usize, usize)>, chars: &[char], language_detection: &LanguageDetection, ssml: bool) {
+    if !language_detection.is_active() {
+        return;
         }
 
-    let ch=character as u32;
+    let languages: Vec<String>=language_detection.alphabets.iter().map(|(_, language)| language.clone()).collect();
+    let mut result: Vec<(usize, usize, usize)>=Vec::new();
+
+    for (alphabet_id, start, end) in chunks.drain(..) {
+        if alphabet_id!=0 {
+            result.push((alphabet_id, start, end));
+            continue;
+            }
+
+        let mut sentence_start=start;
+        let mut sentence=String::new();
+        let mut in_tag=false;
+        for i in start..end {
+            let ch=chars[i];
+
+            if ssml && ch=='<' {
+                in_tag=true;
+                }
+            if in_tag {
+                if ch=='>' {
+                    in_tag=false;
+                    }
+                continue;
+                }
+            sentence.push(ch);
+
+            let sentence_ends=['.', '!', '?', ';', ':', '\n'].contains(&ch) && chars.get(i+1).map(|next| next.is_whitespace() || *next=='<').unwrap_or(true);
+            if sentence_ends || i+1==end {
+                let detected_alphabet_id=match language_detection.detector.detect(&sentence, &languages) {
+                    Some((language, confidence)) if confidence>=language_detection.threshold => language_detection.alphabets[language].0,
+                    _ => 0,
+                    };
+
+                result.push((detected_alphabet_id, sentence_start, i+1));
+                sentence_start=i+1;
+                sentence.clear();
+                }
+            }
+        if sentence_start<end {
+            result.push((0, sentence_start, end));
+            }
+        }
+
+    chunks.extend(result);
+    join_neighbouring_chunks(chunks);
+    }
+//Gives chunks of alphabets of a group, which neighbour a chunk of its leading alphabet in the same sentence, to the leading alphabet. Chunks joined this way pass it further, so for example a whole Japanese sentence with kanji and kana ends up in one chunk, while kanji surrounded by latin text or forming a sentence of their own keep their own alphabet.
+fn apply_alphabet_groups(chunks: &mut Vec<(usize, usize, usize)>, chars: &[char], chunk_merging: &ChunkMerging, ssml: bool) {
+    if chunk_merging.alphabet_groups.len()==0 {
+        return;
+        }
+
+    //Chunks of members are split into sentences, so only sentences neighbouring a leading chunk are taken over.
+    let is_member=|alphabet_id: usize| chunk_merging.alphabet_groups.iter().any(|(_, member_ids)| member_ids.contains(&alphabet_id));
+    let mut sentences: Vec<(usize, usize, usize)>=Vec::new();
+    for (alphabet_id, start, end) in chunks.drain(..) {
+        if !is_member(alphabet_id) {
+            sentences.push((alphabet_id, start, end));
+            continue;
+            }
+
+        let mut sentence_start=start;
+        let mut in_tag=false;
+        let mut i=start;
+        while i<end {
+            let ch=chars[i];
+            i+=1;
+
+            if ssml && ch=='<' {
+                in_tag=true;
+                }
+            if in_tag {
+                if ch=='>' {
+                    in_tag=false;
+                    }
+                continue;
+                }
+            if !SENTENCE_TERMINATORS.contains(&ch) {
+                continue;
+                }
+
+            //Following terminators, whitespace and closing tags still belong to the sentence.
+            loop {
+                if i<end && (SENTENCE_TERMINATORS.contains(&chars[i]) || chars[i].is_whitespace()) {
+                    i+=1;
+                    }
+                else if ssml && i+1<end && chars[i]=='<' && chars[i+1]=='/' {
+                    match chars[i..end].iter().position(|ch| *ch=='>') {
+                        Some(position) => i+=position+1,
+                        None => break,
+                        };
+                    }
+                else {
+                    break;
+                    }
+                }
+
+            if i<end {
+                sentences.push((alphabet_id, sentence_start, i));
+                sentence_start=i;
+                }
+            }
+        sentences.push((alphabet_id, sentence_start, end));
+        }
+    *chunks=sentences;
+
+    let mut changed=true;
+    while changed {
+        changed=false;
+
+        for i in 0..chunks.len() {
+            for (leading_alphabet_id, member_ids) in &chunk_merging.alphabet_groups {
+                if !member_ids.contains(&chunks[i].0) {
+                    continue;
+                    }
+
+                //A sentence ends at the end of a chunk, as punctuation always belongs to the preceding text.
+                let previous_leads=i>0 && chunks[i-1].0==*leading_alphabet_id && !ends_sentence(&chars[chunks[i-1].1..chunks[i-1].2], ssml);
+                let next_leads=i+1<chunks.len() && chunks[i+1].0==*leading_alphabet_id && !ends_sentence(&chars[chunks[i].1..chunks[i].2], ssml);
+                if previous_leads || next_leads {
+                    chunks[i].0= *leading_alphabet_id;
+                    changed=true;
+                    break;
+                    }
+                }
+            }
+        }
+
+    join_neighbouring_chunks(chunks);
+    }
+//Checks, whether the text ends with a sentence terminator, possibly followed by other punctuation and tags.
+fn ends_sentence(text: &[char], ssml: bool) -> bool {
+    let mut in_tag=false;
+    for ch in text.iter().rev() {
+        if ssml && *ch=='>' {
+            in_tag=true;
+            }
+        if in_tag {
+            if *ch=='<' {
+                in_tag=false;
+                }
+            continue;
+            }
+
+        if SENTENCE_TERMINATORS.contains(ch) {
+            return true;
+            }
+        if ch.is_alphanumeric() {
+            return false;
+            }
+        }
+
+    false
+    }
+//Joins neighbouring chunks of the same alphabet.
+fn join_neighbouring_chunks(chunks: &mut Vec<(usize, usize, usize)>) {
+    let mut result: Vec<(usize, usize, usize)>=Vec::new();
+
+    for chunk in chunks.drain(..) {
+        match result.last_mut() {
+            Some(last) if last.0==chunk.0 && last.2==chunk.1 => last.2=chunk.2,
+            _ => result.push(chunk),
+            };
+        }
+
+    *chunks=result;
+    }
+//Merges chunks shorter than the minimum length of their alphabet into their neighbours. A short chunk between two chunks of the same alphabet joins them, a short chunk of an alphabet preferring neighbours is appended to the previous chunk, or prepended to the next one at the beginning of the text.
+fn merge_short_chunks(chunks: &mut Vec<(usize, usize, usize)>, chars: &[char], punctuation_characters: &HashSet<char>, chunk_merging: &ChunkMerging, ssml: bool) {
+    let mut i=0;
+
+    while i<chunks.len() && chunks.len()>1 {
+        let (alphabet_id, start, end)=chunks[i];
+        let minimum_length=chunk_merging.minimum_lengths.get(alphabet_id).cloned().unwrap_or(0);
+
+        if count_letters(&chars[start..end], punctuation_characters, ssml)>=minimum_length {
+            i+=1;
+            continue;
+            }
+
+        if i>0 && i+1<chunks.len() && chunks[i-1].0==chunks[i+1].0 {
+            chunks[i-1].2=chunks[i+1].2;
+            chunks.drain(i..i+2);
+            continue;
+            }
+
+        if chunk_merging.preferring_neighbours.get(alphabet_id).cloned().unwrap_or(false) {
+            if i>0 {
+                chunks[i-1].2=end;
+                }
+            else {
+                chunks[i+1].1=start;
+                }
+            chunks.remove(i);
+            continue;
+            }
+
+        i+=1;
+        }
+    }
+//Counts characters, which are read, that is everything except whitespace, punctuation and SSML tags.
+fn count_letters(chars: &[char], punctuation_characters: &HashSet<char>, ssml: bool) -> usize {
+    let mut in_tag=false;
+    let mut result=0;
+
+    for ch in chars {
+        if ssml && *ch=='<' {
+            in_tag=true;
+            }
+        if in_tag {
+            if *ch=='>' {
+                in_tag=false;
+                }
+            continue;
+            }
+
+        if !ch.is_whitespace() && !punctuation_characters.contains(ch) {
+            result+=1;
+            }
+        }
+
+    result
+    }
+//Makes each chunk a well-formed SSML on its own. Elements open at the end of a chunk are closed there and reopened at the beginning of the next one, after its leading index marks, so they still can be reported by Chinfusor. Speak tags are dropped, as each chunk is wrapped into its own when spoken, and so are closing tags without a matching opening one.
+fn balance_ssml_tags(chunks: Vec<LanguageChunk>) -> Vec<LanguageChunk> {
+    //Names and full opening tags of elements open at the current position
+    let mut open_tags: Vec<(String, String)>=Vec::new();
+    let mut result: Vec<LanguageChunk>=Vec::new();
+
+    for LanguageChunk(alphabet_id, text) in chunks {
+        let reopened_tags: String=open_tags.iter().map(|(_, tag)| tag.as_str()).collect();
+
+        let mut body=String::new();
+        let mut rest=text.as_str();
+        while let Some(tag_start)=rest.find('<') {
+            let tag_end=match rest[tag_start..].find('>') {
+                Some(position) => tag_start+position+1,
+                None => break,
+                };
+            let tag=&rest[tag_start..tag_end];
+            body.push_str(&rest[..tag_start]);
+            rest=&rest[tag_end..];
+
+            match parse_ssml_tag(tag) {
+                SsmlTag::Opening(name) if name!="speak" => {
+                    body.push_str(tag);
+                    open_tags.push((name, tag.to_string()));
+                    },
+                SsmlTag::Closing(name) if name!="speak" => {
+                    if let Some(position)=open_tags.iter().rposition(|(open_name, _)| *open_name==name) {
+                        //Elements left open inside this one are closed together with it.
+                        for (name, _) in open_tags.drain(position..).rev() {
+                            body.push_str(&format!("</{}>", name));
+                            }
+                        }
+                    },
+                SsmlTag::SelfClosing(name) if name!="speak" => body.push_str(tag),
+                _ => {},
+                };
+            }
+        body.push_str(rest);
+
+        let body=body.trim();
+        let closing_tags: String=open_tags.iter().rev().map(|(name, _)| format!("</{}>", name)).collect();
+
+        //Leading marks must stay in front of the reopened tags.
+        let (_, rest)=split_leading_marks(body);
+        let marks=body[..body.len()-rest.len()].trim_end();
+
+        result.push(LanguageChunk(alphabet_id, format!("{}{}{}{}", marks, reopened_tags, rest, closing_tags)));
+        }
+
+    result
+    }
+fn parse_ssml_tag(tag: &str) -> SsmlTag {
+    let content=tag.trim_start_matches('<').trim_end_matches('>');
+
+    if content.starts_with('!') || content.starts_with('?') {
+        return SsmlTag::Other;
+        }
+
+    let closing=content.starts_with('/');
+    let self_closing=content.ends_with('/');
+    let name: String=content.trim_start_matches('/').chars()
+    .take_while(|ch| !ch.is_whitespace() && *ch!='/')
+    .collect();
+
+    if closing {
+        SsmlTag::Closing(name)
+        }
+    else if self_closing {
+        SsmlTag::SelfClosing(name)
+        }
+    else {
+        SsmlTag::Opening(name)
+        }
+    }
+//Separates index marks at the beginning of a chunk from the rest of its text. Such marks are reported by Chinfusor itself, right before the chunk is passed to its engine.
+pub fn split_leading_marks(text: &str) -> (Vec<String>, &str) {
+    let mut marks=Vec::new();
+    let mut rest=text;
+
+    while let Some(captures)=LEADING_MARK_MATCHING_REGEX.captures(rest) {
+        let name=captures.get(2).or(captures.get(3)).unwrap();
+        marks.push(name.as_str().to_string());
+
+        rest=&rest[captures.get(0).unwrap().end()..];
+        }
+
+    (marks, rest.trim_start())
+    }
+pub fn identify_character(character: char, alphabets_scheme: &[u32]) -> usize {
+    find_alphabet_id(character as u32, alphabets_scheme).unwrap_or(0)
+    }
+//Returns id of the alphabet, in whose range the character is, or None if it isn't in any range.
+fn find_alphabet_id(ch: u32, alphabets_scheme: &[u32]) -> Option<usize> {
+    if alphabets_scheme.len()==0 {
+        return None;
+        }
 
     if ch<alphabets_scheme[0] || ch>alphabets_scheme[alphabets_scheme.len()-2] {
-        return 0;
+        return None;
         }
     if ch<=alphabets_scheme[1] {
-        return alphabets_scheme[2] as usize;
+        return Some(alphabets_scheme[2] as usize);
         }
 
     for i in (3..alphabets_scheme.len()).step_by(3) {
         let (lower_boundary, upper_boundary)=(alphabets_scheme[i], alphabets_scheme[i+1]);
 
         if ch<lower_boundary {
-            return 0;
+            return None;
             }
         if ch<=upper_boundary {
-            return alphabets_scheme[i+2] as usize;
+            return Some(alphabets_scheme[i+2] as usize);
             }
         }
 
-    return 0;
+    None
     }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use super::super::Config;
+    use super::super::{Config, ConfigError};
 
     #[test]
     fn chinese_test() {
@@ -138,7 +616,24 @@ mod tests {
         let text="Hello, this is an experimental test. 你好，我是人。 There is also some mixed 过 text. Hmm, I must refresh my Chinese, as I don't remember a word. :D Here is also a test question: 他又妈妈？";
         let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "Hello, this is an experimental test.".to_string()), LanguageChunk(1, "你好，我是人。".to_string()), LanguageChunk(0, "There is also some mixed".to_string()), LanguageChunk(1, "过".to_string()), LanguageChunk(0, "text. Hmm, I must refresh my Chinese, as I don't remember a word. :D Here is also a test question:".to_string()), LanguageChunk(1, "他又妈妈？".to_string())];
 
-        assert_eq!(expected_result, parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, false));
+        assert_eq!(expected_result, parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, &ChunkingOptions::default(), false));
+
+        //Single characters surrounded by latin text are read by the latin alphabet, longer chunks and chunks between different alphabets are kept.
+        config.load_configuration_from_string("punctuation_characters: ,.?，。？- :()\nminimum_chunk_length: 2");
+        let text="the word 过 here, 你好 and 我 你好";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "the word 过 here,".to_string()), LanguageChunk(1, "你好".to_string()), LanguageChunk(0, "and".to_string()), LanguageChunk(1, "我 你好".to_string())];
+        assert_eq!(expected_result, parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, &ChunkingOptions {chunk_merging: config.generate_chunk_merging(), ..ChunkingOptions::default()}, false));
+
+        //Chunks of alphabets preferring neighbours are merged also at boundaries of different alphabets.
+        config.load_configuration_from_string("punctuation_characters: ,.?，。？- :()\nminimum_chunk_length: 0, chinese 2\nprefer_neighbours: chinese");
+        let text="我 hello, 你好 and 我";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "我 hello,".to_string()), LanguageChunk(1, "你好".to_string()), LanguageChunk(0, "and 我".to_string())];
+        assert_eq!(expected_result, parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, &ChunkingOptions {chunk_merging: config.generate_chunk_merging(), ..ChunkingOptions::default()}, false));
+        assert_eq!(ChunkMerging {minimum_lengths: vec![0, 2, 0], preferring_neighbours: vec![false, true, false], alphabet_groups: vec![]}, config.generate_chunk_merging());
+
+        let report=config.load_configuration_from_string("minimum_chunk_length: 2, chinese\nprefer_neighbours: ");
+        assert_eq!(vec![ConfigError::new("settings.conf", 1, 23, "minimum_chunk_length", "expected a number or an alphabet name followed by a number, found \"chinese\"")], report.errors);
+        assert_eq!(ChunkMerging {minimum_lengths: vec![0, 2, 0], preferring_neighbours: vec![false, false, false], alphabet_groups: vec![]}, config.generate_chunk_merging());
         }
 
     #[test]
@@ -150,7 +645,179 @@ mod tests {
         let text="This is a Katyusha test. It consists of writing Катюша and seeing, whether this thing can parse it, as I don't have a cyrillic keyboard.";
         let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "This is a Katyusha test. It consists of writing".to_string()), LanguageChunk(2, "Катюша".to_string()), LanguageChunk(0, "and seeing, whether this thing can parse it, as I don't have a cyrillic keyboard.".to_string())];
 
-        assert_eq!(expected_result, parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, false));
+        assert_eq!(expected_result, parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, &ChunkingOptions::default(), false));
+
+        //Merging works with SSML too, tags are not counted as letters.
+        config.load_configuration_from_string("punctuation_characters: ,.?，。？- :()\nminimum_chunk_length: 3");
+        let text="<mark name=\"0\"/>Say <emphasis>да</emphasis> twice";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "<mark name=\"0\"/>Say <emphasis>да</emphasis> twice".to_string())];
+        assert_eq!(expected_result, parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, &ChunkingOptions {chunk_merging: config.generate_chunk_merging(), ..ChunkingOptions::default()}, true));
+        }
+
+    #[test]
+    fn script_names_test() {
+        let mut config=Config::new();
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nchinese,Han; CJK Symbols and Punctuation; u0xFF0C-u0xFF0C,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no\ncyrillic,cyrillic,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,ru,male1,some,10,50,2,100,no\ngreek,block:Greek and Coptic,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,el,male1,some,10,50,2,100,no");
+        let alphabets_scheme=config.generate_alphabets_scheme();
+
+        assert_eq!(4, config.engines.len());
+        assert_eq!(1, identify_character('你', &alphabets_scheme));
+        assert_eq!(1, identify_character('㐀', &alphabets_scheme)); //CJK Extension A
+        assert_eq!(1, identify_character('𠀀', &alphabets_scheme)); //CJK Extension B
+        assert_eq!(1, identify_character('。', &alphabets_scheme));
+        assert_eq!(1, identify_character('，', &alphabets_scheme));
+        assert_eq!(2, identify_character('ж', &alphabets_scheme));
+        assert_eq!(3, identify_character('λ', &alphabets_scheme));
+        assert_eq!(0, identify_character('a', &alphabets_scheme));
+
+        //Unknown names make the whole line invalid
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nklingon,Klingon,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,tlh,male1,some,10,50,2,100,no");
+        assert_eq!(1, config.engines.len());
+
+        //Prefixes are case insensitive, characters only lowercasing to them don't count.
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\ncyrillic,BLOCK:Cyrillic,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,ru,male1,some,10,50,2,100,no\ngreek,bloc\u{212A}:Greek and Coptic,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,el,male1,some,10,50,2,100,no");
+        assert_eq!(2, config.engines.len());
+        assert_eq!(1, identify_character('ж', &config.generate_alphabets_scheme()));
+        }
+
+    #[test]
+    fn index_marks_test() {
+        let mut config=Config::new();
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nchinese,u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no");
+        config.load_configuration_from_string("punctuation_characters: ,.?，。？- :()");
+
+        let text="<mark name=\"0\"/>Hello, <mark name=\"7\"/>world. <mark name=\"14\"/>你好，<mark name=\"17\"/>我是人。<mark name=\"22\"/> <mark name=\"23\"/>Bye.";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "<mark name=\"0\"/>Hello, <mark name=\"7\"/>world.".to_string()), LanguageChunk(1, "<mark name=\"14\"/>你好，<mark name=\"17\"/>我是人。".to_string()), LanguageChunk(0, "<mark name=\"22\"/> <mark name=\"23\"/>Bye.".to_string())];
+
+        let result=parse_text(text, &config.generate_alphabets_scheme(), &config.punctuation_characters, &ChunkingOptions::default(), true);
+        assert_eq!(expected_result, result);
+
+        assert_eq!((vec!["22".to_string(), "23".to_string()], "Bye."), split_leading_marks(&result[2].1));
+        assert_eq!((vec!["14".to_string()], "你好，<mark name=\"17\"/>我是人。"), split_leading_marks(&result[1].1));
+        assert_eq!((Vec::<String>::new(), "Hello"), split_leading_marks("Hello"));
+        }
+
+    #[test]
+    fn neutral_characters_test() {
+        let mut config=Config::new();
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nchinese,u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no");
+        let alphabets_scheme=config.generate_alphabets_scheme();
+        assert_eq!(NeutralCharacterPolicy::Previous, config.neutral_character_policy);
+
+        assert_eq!(vec![LanguageChunk(1, "我有3个苹果".to_string())], parse_text("我有3个苹果", &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, ..ChunkingOptions::default()}, false));
+        assert_eq!(vec![LanguageChunk(1, "3个苹果".to_string())], parse_text("3个苹果", &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, ..ChunkingOptions::default()}, false));
+        assert_eq!(vec![LanguageChunk(1, "我有3个苹果".to_string())], parse_text("我有3个苹果", &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Next, ..ChunkingOptions::default()}, false));
+        assert_eq!(vec![LanguageChunk(1, "我有".to_string()), LanguageChunk(0, "3".to_string()), LanguageChunk(1, "个苹果".to_string())], parse_text("我有3个苹果", &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions::default(), false));
+
+        //Between chunks of different alphabets, the policy decides.
+        let text="Room 12 房间 42 is free";
+        assert_eq!(vec![LanguageChunk(0, "Room 12".to_string()), LanguageChunk(1, "房间 42".to_string()), LanguageChunk(0, "is free".to_string())], parse_text(text, &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, ..ChunkingOptions::default()}, false));
+        assert_eq!(vec![LanguageChunk(0, "Room".to_string()), LanguageChunk(1, "12 房间".to_string()), LanguageChunk(0, "42 is free".to_string())], parse_text(text, &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Next, ..ChunkingOptions::default()}, false));
+
+        //Neutral characters after a tag still follow the policy, the tag goes with them.
+        let text="你好<mark name=\"1\"/>3 hello";
+        assert_eq!(vec![LanguageChunk(1, "你好<mark name=\"1\"/>3".to_string()), LanguageChunk(0, "hello".to_string())], parse_text(text, &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, ..ChunkingOptions::default()}, true));
+        assert_eq!(vec![LanguageChunk(1, "你好".to_string()), LanguageChunk(0, "<mark name=\"1\"/>3 hello".to_string())], parse_text(text, &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Next, ..ChunkingOptions::default()}, true));
+        assert_eq!(vec![LanguageChunk(1, "你好3".to_string()), LanguageChunk(0, "<mark name=\"1\"/>hello".to_string())], parse_text("你好3 <mark name=\"1\"/>hello", &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, ..ChunkingOptions::default()}, true));
+
+        //Explicitly assigned characters are never neutral.
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nchinese,u0x4E00-u0x9FA5+u0x30-u0x39,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no");
+        assert_eq!(vec![LanguageChunk(0, "Room".to_string()), LanguageChunk(1, "12".to_string())], parse_text("Room 12", &config.generate_alphabets_scheme(), &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, ..ChunkingOptions::default()}, false));
+
+        let report=config.load_configuration_from_string("neutral_characters: next\nneutral_characters: surrounding");
+        assert_eq!(NeutralCharacterPolicy::Next, config.neutral_character_policy);
+        assert_eq!(vec![ConfigError::new("settings.conf", 2, 21, "neutral_characters", "expected previous, next or none, found \"surrounding\"")], report.errors);
+        }
+
+    #[test]
+    fn language_detection_test() {
+        let mut config=Config::new();
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,en,male1,some,10,50,2,100,no\nslovak,detected,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\ngerman,detected,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,de,male1,some,10,50,2,100,no\nchinese,u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no");
+        config.load_configuration_from_string("punctuation_characters: ,.?，。？- :()");
+        let alphabets_scheme=config.generate_alphabets_scheme();
+        let options=ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, language_detection: config.generate_language_detection(), ..ChunkingOptions::default()};
+
+        //Alphabets selected by detection have no ranges, but they're not the latin alphabet.
+        assert_eq!(4, config.engines.len());
+        assert_eq!(vec![(0, "en".to_string()), (1, "sk".to_string()), (2, "de".to_string())], options.language_detection.alphabets);
+
+        let text="This is a test of the system. Dobrý deň, ako sa máš? Ich habe das Buch gelesen, 你好. Hello world.";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "This is a test of the system.".to_string()), LanguageChunk(1, "Dobrý deň, ako sa máš?".to_string()), LanguageChunk(2, "Ich habe das Buch gelesen,".to_string()), LanguageChunk(3, "你好.".to_string()), LanguageChunk(0, "Hello world.".to_string())];
+        assert_eq!(expected_result, parse_text(text, &alphabets_scheme, &config.punctuation_characters, &options, false));
+
+        //Sentences, in which the detector isn't confident enough, are read by the latin alphabet.
+        let text="Ich bin in the house. Das ist gut.";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "Ich bin in the house.".to_string()), LanguageChunk(2, "Das ist gut.".to_string())];
+        assert_eq!(expected_result, parse_text(text, &alphabets_scheme, &config.punctuation_characters, &options, false));
+
+        config.load_configuration_from_string("language_detection_threshold: 1");
+        let text="Das ist gut.";
+        assert_eq!(vec![LanguageChunk(0, text.to_string())], parse_text(text, &alphabets_scheme, &config.punctuation_characters, &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, language_detection: config.generate_language_detection(), ..ChunkingOptions::default()}, false));
+
+        assert_eq!(Some((1, 2.0/3.0)), WordListDetector::new().detect("Das ist gut", &["en".to_string(), "de-AT".to_string()]));
+        assert_eq!(None, WordListDetector::new().detect("Chinfusor", &["en".to_string(), "de".to_string()]));
+
+        let report=config.load_configuration_from_string("language_detection_threshold: 2");
+        assert_eq!(vec![ConfigError::new("settings.conf", 1, 31, "language_detection_threshold", "expected a number from 0 to 1, found \"2\"")], report.errors);
+        }
+
+    #[test]
+    fn alphabet_groups_test() {
+        let mut config=Config::new();
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,en,male1,some,10,50,2,100,no\nchinese,Han,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no\njapanese,Hiragana; Katakana,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,ja,male1,some,10,50,2,100,no");
+        config.load_configuration_from_string("punctuation_characters: ,.?，。？- :()\nalphabet_groups: japanese+chinese");
+        let alphabets_scheme=config.generate_alphabets_scheme();
+        let options=ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, chunk_merging: config.generate_chunk_merging(), ..ChunkingOptions::default()};
+        assert_eq!(vec![(2, vec![1])], options.chunk_merging.alphabet_groups);
+
+        let split=|text: &str, options: &ChunkingOptions| parse_text(text, &alphabets_scheme, &config.punctuation_characters, options, false);
+
+        assert_eq!(vec![LanguageChunk(2, "食べます".to_string())], split("食べます", &options));
+        assert_eq!(vec![LanguageChunk(2, "私は東京タワーへ行きます。".to_string())], split("私は東京タワーへ行きます。", &options));
+
+        //Kanji in a sentence of their own stay Chinese.
+        assert_eq!(vec![LanguageChunk(2, "私は行きます。".to_string()), LanguageChunk(1, "我爱你。".to_string())], split("私は行きます。我爱你。", &options));
+        assert_eq!(vec![LanguageChunk(1, "我爱你！".to_string()), LanguageChunk(2, "私は行きます".to_string())], split("我爱你！私は行きます", &options));
+        assert_eq!(vec![LanguageChunk(1, "我爱你。".to_string()), LanguageChunk(2, "私は行きます".to_string())], split("我爱你。私は行きます", &options));
+        assert_eq!(vec![LanguageChunk(2, "<s>私は行きます。</s>".to_string()), LanguageChunk(1, "<s>我爱你。</s>".to_string())], parse_text("<s>私は行きます。</s><s>我爱你。</s>", &alphabets_scheme, &config.punctuation_characters, &options, true));
+        assert_eq!(vec![LanguageChunk(1, "食".to_string()), LanguageChunk(2, "べます".to_string())], split("食べます", &ChunkingOptions {neutral_character_policy: NeutralCharacterPolicy::Previous, ..ChunkingOptions::default()}));
+
+        //Kanji not touching kana stay Chinese.
+        let text="I like 寿司 and ラーメン 拉面 there";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "I like".to_string()), LanguageChunk(1, "寿司".to_string()), LanguageChunk(0, "and".to_string()), LanguageChunk(2, "ラーメン 拉面".to_string()), LanguageChunk(0, "there".to_string())];
+        assert_eq!(expected_result, split(text, &options));
+
+        let report=config.load_configuration_from_string("alphabet_groups: japanese+chinese, korean");
+        assert_eq!(vec![ConfigError::new("settings.conf", 1, 18, "alphabet_groups", "expected names of alphabets joined by +, found \"korean\"")], report.errors);
+        }
+
+    #[test]
+    fn ssml_test() {
+        let mut config=Config::new();
+        config.load_alphabets_from_string("latin,*,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,sk,male1,some,10,50,2,100,no\nchinese,u0x4E00-u0x9FA5,/usr/lib/speech-dispatcher-modules/sd_espeak-ng,/etc/speech-dispatcher/modules/espeak-ng.conf,cmn,male1,some,10,50,2,100,no");
+        config.load_configuration_from_string("punctuation_characters: ,.?，。？- :()");
+        let alphabets_scheme=config.generate_alphabets_scheme();
+
+        //Open elements are closed and reopened at chunk boundaries, leading marks stay in front of them.
+        let text="<speak><prosody rate=\"slow\">Hello, <mark name=\"7\"/>你好 <emphasis>world</emphasis>.</prosody></speak>";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "<prosody rate=\"slow\">Hello,</prosody>".to_string()), LanguageChunk(1, "<mark name=\"7\"/><prosody rate=\"slow\">你好</prosody>".to_string()), LanguageChunk(0, "<prosody rate=\"slow\"><emphasis>world</emphasis>.</prosody>".to_string())];
+        assert_eq!(expected_result, parse_text(text, &alphabets_scheme, &config.punctuation_characters, &config.generate_chunking_options(), true));
+
+        //Opening tags before a boundary belong to the next chunk, closing tags after it to the previous one.
+        let text="Say <say-as interpret-as=\"characters\">我</say-as> <emphasis>again</emphasis>";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "Say".to_string()), LanguageChunk(1, "<say-as interpret-as=\"characters\">我</say-as>".to_string()), LanguageChunk(0, "<emphasis>again</emphasis>".to_string())];
+        assert_eq!(expected_result, parse_text(text, &alphabets_scheme, &config.punctuation_characters, &config.generate_chunking_options(), true));
+
+        //Unclosed elements are closed at the end, orphaned closing tags are dropped.
+        let text="<s>Hi 你</b>";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "<s>Hi</s>".to_string()), LanguageChunk(1, "<s>你</s>".to_string())];
+        assert_eq!(expected_result, parse_text(text, &alphabets_scheme, &config.punctuation_characters, &config.generate_chunking_options(), true));
+
+        //Text, which isn't split at all, loses its speak tags as well, so it's not wrapped twice.
+        let text="<speak>Hello <mark name=\"6\"/>world</speak>";
+        let expected_result: Vec<LanguageChunk>=vec![LanguageChunk(0, "Hello <mark name=\"6\"/>world".to_string())];
+        assert_eq!(expected_result, parse_text(text, &Vec::new(), &config.punctuation_characters, &config.generate_chunking_options(), true));
+        assert_eq!(vec![LanguageChunk(0, text.to_string())], parse_text(text, &Vec::new(), &config.punctuation_characters, &config.generate_chunking_options(), false));
         }
 
     }
@@ -0,0 +1,100 @@
+//Built-in Unicode data, which allow alphabets to be specified by names of scripts and blocks instead of numeric ranges.
+
+use unicode_blocks::find_unicode_block;
+use unicode_script::{Script, UnicodeScript};
+
+lazy_static! {
+    //Continuous ranges of characters with the same script, covering the whole Unicode.
+    static ref SCRIPT_RANGES: Vec<(u32, u32, Script)>=generate_script_ranges();
+    }
+
+//Returns ranges of characters belonging to the script or block of the given name. Names are compared case insensitively, ignoring spaces, underscores and hyphens. Scripts take precedence over blocks of the same name, a block can be requested explicitly by prefixing its name with "block:".
+pub fn resolve_name(name: &str) -> Option<Vec<(u32, u32)>> {
+    let name=name.trim();
+
+    //Prefixes are compared on the original string, so it's sliced only where it really contains them.
+    if name.get(..6).map(|prefix| prefix.eq_ignore_ascii_case("block:")).unwrap_or(false) {
+        return resolve_block(&name[6..]);
+        }
+
+    let name=if name.get(..7).map(|prefix| prefix.eq_ignore_ascii_case("script:")).unwrap_or(false) {
+        &name[7..]
+        } else {
+        name
+        };
+
+    resolve_script(name).or_else(|| resolve_block(name))
+    }
+
+//Returns true for characters, which aren't specific to any script, like digits, symbols, emoji or combining marks.
+pub fn is_neutral(ch: char) -> bool {
+    let script=ch.script();
+
+    script==Script::Common || script==Script::Inherited
+    }
+
+fn resolve_script(name: &str) -> Option<Vec<(u32, u32)>> {
+    let name=normalize_name(name);
+
+    let result: Vec<(u32, u32)>=SCRIPT_RANGES.iter()
+    .filter(|(_, _, script)| normalize_name(script.full_name())==name || normalize_name(script.short_name())==name)
+    .map(|(start, end, _)| (*start, *end))
+    .collect();
+
+    if result.len()>0 {
+        Some(result)
+        }
+    else {
+        None
+        }
+    }
+fn resolve_block(name: &str) -> Option<Vec<(u32, u32)>> {
+    let name=normalize_name(name);
+    let mut code=0;
+
+    //Blocks are continuous, so it's enough to jump from one to the next.
+    while code<=0x10FFFF {
+        match std::char::from_u32(code).and_then(find_unicode_block) {
+            Some(block) => {
+                if normalize_name(block.name())==name {
+                    return Some(vec![(block.start(), block.end())]);
+                    }
+
+                code=block.end()+1;
+                },
+            None => code+=1,
+            };
+        }
+
+    None
+    }
+fn generate_script_ranges() -> Vec<(u32, u32, Script)> {
+    let mut result: Vec<(u32, u32, Script)>=Vec::new();
+
+    for code in 0..=0x10FFFF {
+        let script=match std::char::from_u32(code) {
+            Some(ch) => ch.script(),
+            None => continue,
+            };
+        if script==Script::Unknown {
+            continue;
+            }
+
+        if let Some(last)=result.last_mut() {
+            if last.2==script && last.1+1==code {
+                last.1=code;
+                continue;
+                }
+            }
+
+        result.push((code, code, script));
+        }
+
+    result
+    }
+fn normalize_name(name: &str) -> String {
+    name.chars()
+    .filter(|ch| !ch.is_whitespace() && *ch!='_' && *ch!='-')
+    .flat_map(|ch| ch.to_lowercase())
+    .collect()
+    }